@@ -1,10 +1,13 @@
 #[macro_use]
 extern crate criterion;
 
-use clockpro_cache::ClockProCache;
+use clockpro_cache::{BoxedClockProCache, ClockProCache, SlabGrowth};
 use criterion::{black_box, Criterion};
 use rand::thread_rng;
-use rand_distr::{Distribution, Normal, Uniform};
+use rand_distr::{Distribution, Normal, Uniform, Zipf};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 fn bench_sequence(c: &mut Criterion) {
     c.bench_function("bench_sequence", |b| {
@@ -72,10 +75,277 @@ fn bench_composite_normal(c: &mut Criterion) {
     });
 }
 
+fn bench_randomized_start(c: &mut Criterion) {
+    // A loop slightly larger than the cache is the worst case for a
+    // deterministic sweep: every access follows the eviction of its key.
+    fn looping_trace(cache: &mut ClockProCache<u64, u64>) -> u64 {
+        let mut hits = 0;
+        for i in 0..10_000 {
+            let n = i % 80;
+            if cache.get(&n).is_some() {
+                hits += 1;
+            } else {
+                cache.insert(n, n);
+            }
+        }
+        hits
+    }
+
+    c.bench_function("bench_deterministic_start", |b| {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(68).unwrap();
+        b.iter(|| black_box(looping_trace(&mut cache)));
+    });
+    c.bench_function("bench_randomized_start", |b| {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(68)
+            .randomized_start(0x5eed)
+            .build()
+            .unwrap();
+        b.iter(|| black_box(looping_trace(&mut cache)));
+    });
+}
+
+fn bench_frequency_counter(c: &mut Criterion) {
+    // A frequency-skewed trace over many more keys than the cache can hold
+    let mut rng = thread_rng();
+    let zipf = Zipf::new(10_000, 1.1).unwrap();
+    let trace: Vec<u64> = zipf
+        .sample_iter(&mut rng)
+        .take(10_000)
+        .map(|x| x as u64)
+        .collect();
+    let replay = |cache: &mut ClockProCache<u64, u64>| {
+        for &n in &trace {
+            if cache.get(&n).is_none() {
+                cache.insert(n, n);
+            }
+        }
+    };
+
+    c.bench_function("bench_reference_bit_zipf", |b| {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(500).unwrap();
+        b.iter(|| replay(black_box(&mut cache)));
+    });
+    c.bench_function("bench_frequency_counter_zipf", |b| {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(500)
+            .frequency_counter_limit(3)
+            .build()
+            .unwrap();
+        b.iter(|| replay(black_box(&mut cache)));
+    });
+}
+
+fn bench_negative_bloom(c: &mut Criterion) {
+    // Mostly lookups for keys that were never inserted
+    fn lookups(cache: &mut ClockProCache<u64, u64>) -> usize {
+        (0..10_000u64)
+            .filter(|i| cache.get(&(i * 7919)).is_some())
+            .count()
+    }
+    let fill = |mut cache: ClockProCache<u64, u64>| {
+        for i in 0..10_000 {
+            cache.insert(i * 7919 * 16, i);
+        }
+        cache
+    };
+
+    c.bench_function("bench_misses", |b| {
+        let mut cache = fill(ClockProCache::new(10_000).unwrap());
+        b.iter(|| black_box(lookups(&mut cache)));
+    });
+    c.bench_function("bench_misses_negative_bloom", |b| {
+        let mut cache = fill(
+            ClockProCache::builder(10_000)
+                .negative_bloom(20_000)
+                .build()
+                .unwrap(),
+        );
+        b.iter(|| black_box(lookups(&mut cache)));
+    });
+}
+
+fn bench_u64_lookups(c: &mut Criterion) {
+    // Guards the cost of the lookup path itself for small `Copy` keys
+    let mut cache: ClockProCache<u64, u64> = ClockProCache::new(1024).unwrap();
+    for i in 0..1024 {
+        cache.insert(i, i);
+    }
+
+    c.bench_function("bench_u64_get_hit", |b| {
+        b.iter(|| {
+            for i in 0..1024 {
+                black_box(cache.get(&i));
+            }
+        });
+    });
+    c.bench_function("bench_u64_get_miss", |b| {
+        b.iter(|| {
+            for i in 1024..2048 {
+                black_box(cache.get(&i));
+            }
+        });
+    });
+    c.bench_function("bench_u64_get_mut_hit", |b| {
+        b.iter(|| {
+            for i in 0..1024 {
+                black_box(cache.get_mut(&i));
+            }
+        });
+    });
+}
+
+fn bench_large_values(c: &mut Criterion) {
+    // With preallocated storage, growing the capacity moves every slot of
+    // the node storage
+    type Large = [u64; 128];
+
+    c.bench_function("bench_grow_inline_large_values", |b| {
+        b.iter(|| {
+            let mut cache: ClockProCache<u64, Large> = ClockProCache::builder(64)
+                .slab_growth(SlabGrowth::Preallocate)
+                .build()
+                .unwrap();
+            for i in 0..64 {
+                cache.insert(i, [i; 128]);
+            }
+            for capacity in [128, 256, 512, 1024] {
+                cache.set_capacity(capacity).unwrap();
+            }
+            black_box(cache.len())
+        });
+    });
+    c.bench_function("bench_grow_boxed_large_values", |b| {
+        b.iter(|| {
+            let mut cache: BoxedClockProCache<u64, Large> = ClockProCache::builder(64)
+                .slab_growth(SlabGrowth::Preallocate)
+                .build()
+                .unwrap();
+            for i in 0..64 {
+                cache.insert(i, Box::new([i; 128]));
+            }
+            for capacity in [128, 256, 512, 1024] {
+                cache.set_capacity(capacity).unwrap();
+            }
+            black_box(cache.len())
+        });
+    });
+}
+
+fn bench_large_cache_sweep(c: &mut Criterion) {
+    // Eviction-heavy work on a cache whose nodes do not fit in the CPU
+    // caches; compare runs with and without the `prefetch` feature
+    let keys: Vec<u64> = Uniform::new(0, 1 << 22)
+        .sample_iter(thread_rng())
+        .take(1 << 16)
+        .collect();
+    let mut cache: ClockProCache<u64, [u64; 8]> = ClockProCache::new(1 << 20).unwrap();
+    for i in 0..1 << 21 {
+        cache.insert(i, [i; 8]);
+    }
+
+    c.bench_function("bench_large_cache_sweep", |b| {
+        b.iter(|| {
+            for &key in &keys {
+                if cache.get(&key).is_none() {
+                    cache.insert(key, [key; 8]);
+                }
+            }
+        });
+    });
+}
+
+fn bench_slab_growth(c: &mut Criterion) {
+    // Measures the slowest insertion of a bulk load into a cache that has
+    // just been grown, which is where the storage reallocations show up
+    const ENTRIES: u64 = 1 << 17;
+
+    for (name, growth) in [
+        ("doubling", SlabGrowth::Doubling),
+        ("increment", SlabGrowth::Increment(4096)),
+        ("preallocate", SlabGrowth::Preallocate),
+    ] {
+        c.bench_function(&format!("bench_slab_growth_worst_insert_{}", name), |b| {
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let mut cache: ClockProCache<u64, [u64; 8]> = ClockProCache::builder(1024)
+                        .slab_growth(growth)
+                        .build()
+                        .unwrap();
+                    cache.set_capacity(ENTRIES as usize).unwrap();
+                    let mut worst = Duration::ZERO;
+                    for i in 0..ENTRIES {
+                        let start = Instant::now();
+                        cache.insert(i, [i; 8]);
+                        worst = worst.max(start.elapsed());
+                    }
+                    total += worst;
+                }
+                total
+            });
+        });
+    }
+}
+
+fn bench_insert_hashing(c: &mut Criterion) {
+    // Keys whose hash dominates the cost of an insertion, with the hashes
+    // counted. The lookup before the insertion stands for the one `insert`
+    // used to do, before it went through the entry API
+    static HASHES: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Clone, PartialEq, Eq)]
+    struct CountedKey([u64; 32]);
+
+    impl Hash for CountedKey {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            HASHES.fetch_add(1, Ordering::Relaxed);
+            self.0.hash(state);
+        }
+    }
+
+    let keys: Vec<CountedKey> = Uniform::new(0, 1024)
+        .sample_iter(thread_rng())
+        .take(1000)
+        .map(|n| CountedKey([n; 32]))
+        .collect();
+    for (name, lookup_first) in [
+        ("bench_insert_counted_hash", false),
+        ("bench_lookup_then_insert_counted_hash", true),
+    ] {
+        let mut cache: ClockProCache<CountedKey, u64> = ClockProCache::new(256).unwrap();
+        let mut inserts = 0;
+        HASHES.store(0, Ordering::Relaxed);
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                for key in &keys {
+                    if lookup_first {
+                        black_box(cache.contains_key(key));
+                    }
+                    black_box(cache.insert(key.clone(), 0));
+                }
+                inserts += keys.len();
+            });
+        });
+        let hashes = HASHES.load(Ordering::Relaxed);
+        println!(
+            "{}: {:.2} hashes per insert",
+            name,
+            hashes as f64 / inserts as f64
+        );
+    }
+}
+
 criterion_group!(
     benches,
     bench_sequence,
     bench_composite,
-    bench_composite_normal
+    bench_composite_normal,
+    bench_randomized_start,
+    bench_frequency_counter,
+    bench_negative_bloom,
+    bench_u64_lookups,
+    bench_large_values,
+    bench_large_cache_sweep,
+    bench_slab_growth,
+    bench_insert_hashing
 );
 criterion_main!(benches);
@@ -0,0 +1,431 @@
+use crate::ClockProCache;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+/// A `ClockProCache` behind a lock, with single-flight asynchronous loading.
+///
+/// When several tasks miss on the same key at the same time, only the first
+/// one runs its loader; the others wait for its result instead of loading
+/// the value again. The lock is never held while a loader runs, and this
+/// type does not depend on any particular async runtime.
+///
+/// Loaders may use the cache, but a loader waiting for its own key would
+/// never complete, and a `with_cache` closure cannot reenter the cache while
+/// it holds the lock. Both are detected: the first one panics, and the second
+/// one panics with `with_cache` and fails with `try_with_cache`, instead of
+/// deadlocking.
+pub struct AsyncClockProCache<K, V> {
+    inner: Mutex<Inner<K, V>>,
+}
+
+struct Inner<K, V> {
+    cache: ClockProCache<K, V>,
+    in_flight: HashMap<K, Arc<Flight<V>>>,
+}
+
+struct Flight<V> {
+    state: Mutex<FlightState<V>>,
+}
+
+enum FlightState<V> {
+    Loading(Vec<Waker>),
+    // `None` if the loading task gave up without producing a value
+    Done(Option<V>),
+}
+
+/// Error returned by `AsyncClockProCache::try_with_cache` when the cache is
+/// used from within one of its own `with_cache` closures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReentrantAccess;
+
+impl fmt::Display for ReentrantAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the cache was accessed reentrantly, from a with_cache closure")
+    }
+}
+
+impl std::error::Error for ReentrantAccess {}
+
+thread_local! {
+    // Caches whose lock is held by a `with_cache` closure on this thread
+    static LOCKED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    // Flights whose loader is being polled on this thread
+    static LOADING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+// Registers an address in one of the thread-local lists while alive
+struct Marker(&'static std::thread::LocalKey<RefCell<Vec<usize>>>);
+
+impl Marker {
+    fn new(list: &'static std::thread::LocalKey<RefCell<Vec<usize>>>, addr: usize) -> Self {
+        list.with(|list| list.borrow_mut().push(addr));
+        Marker(list)
+    }
+
+    fn contains(list: &'static std::thread::LocalKey<RefCell<Vec<usize>>>, addr: usize) -> bool {
+        list.with(|list| list.borrow().contains(&addr))
+    }
+}
+
+impl Drop for Marker {
+    fn drop(&mut self) {
+        self.0.with(|list| list.borrow_mut().pop());
+    }
+}
+
+enum Role<V> {
+    Lead(Arc<Flight<V>>),
+    Wait(Arc<Flight<V>>),
+}
+
+impl<K, V> AsyncClockProCache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub fn new(cache: ClockProCache<K, V>) -> Self {
+        AsyncClockProCache {
+            inner: Mutex::new(Inner {
+                cache,
+                in_flight: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn into_inner(self) -> ClockProCache<K, V> {
+        self.inner
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner())
+            .cache
+    }
+
+    /// Runs `f` with exclusive access to the underlying cache.
+    ///
+    /// Panics if called from within another `with_cache` closure on the same
+    /// cache, which would otherwise deadlock.
+    pub fn with_cache<R>(&self, f: impl FnOnce(&mut ClockProCache<K, V>) -> R) -> R {
+        self.try_with_cache(f)
+            .unwrap_or_else(|err| panic!("AsyncClockProCache: {}", err))
+    }
+
+    /// Same as `with_cache`, returning an error instead of panicking on
+    /// reentrant access.
+    pub fn try_with_cache<R>(
+        &self,
+        f: impl FnOnce(&mut ClockProCache<K, V>) -> R,
+    ) -> Result<R, ReentrantAccess> {
+        let mut inner = self.lock()?;
+        let _marker = Marker::new(&LOCKED, self.addr());
+        Ok(f(&mut inner.cache))
+    }
+
+    /// Returns a clone of the cached value for `key`, loading and inserting
+    /// it with `loader` on a miss.
+    ///
+    /// If a load for the same key is already in flight, this waits for it
+    /// instead of calling `loader`. Should that load be cancelled, one of the
+    /// waiting tasks takes over with its own loader.
+    pub async fn get_or_load<F, Fut>(&self, key: K, loader: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let loader = move || {
+            let future = loader();
+            async move { Ok::<_, Infallible>(future.await) }
+        };
+        match self.get_or_insert_with_async(key, loader).await {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Same as `get_or_load`, with a loader that can fail.
+    ///
+    /// On error, nothing is inserted and the error is returned to this caller
+    /// only; tasks that were waiting for this load retry with their own loader.
+    pub async fn get_or_insert_with_async<F, Fut, E>(&self, key: K, f: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let mut f = Some(f);
+        loop {
+            match self.role(&key) {
+                Err(value) => return Ok(value),
+                Ok(Role::Lead(flight)) => {
+                    let guard = LeaderGuard {
+                        cache: self,
+                        key: &key,
+                        flight: &flight,
+                    };
+                    let f = f.take().expect("The loader is only called once");
+                    let value = Loading {
+                        flight: Arc::as_ptr(&flight) as usize,
+                        future: f(),
+                    }
+                    .await?;
+                    guard.complete(value.clone());
+                    return Ok(value);
+                }
+                Ok(Role::Wait(flight)) => {
+                    assert!(
+                        !Marker::contains(&LOADING, Arc::as_ptr(&flight) as usize),
+                        "AsyncClockProCache: a loader waited for its own key, which would never complete"
+                    );
+                    if let Some(value) = (FlightWait { flight: &flight }).await {
+                        return Ok(value);
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns the cached value, or what the caller has to do to get it
+    fn role(&self, key: &K) -> Result<Role<V>, V> {
+        let mut inner = self.locked();
+        if let Some(value) = inner.cache.get(key) {
+            return Err(value.clone());
+        }
+        if let Some(flight) = inner.in_flight.get(key) {
+            return Ok(Role::Wait(flight.clone()));
+        }
+        let flight = Arc::new(Flight {
+            state: Mutex::new(FlightState::Loading(Vec::new())),
+        });
+        inner.in_flight.insert(key.clone(), flight.clone());
+        Ok(Role::Lead(flight))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Inner<K, V>>, ReentrantAccess> {
+        if Marker::contains(&LOCKED, self.addr()) {
+            return Err(ReentrantAccess);
+        }
+        Ok(self.inner.lock().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    fn locked(&self) -> MutexGuard<'_, Inner<K, V>> {
+        self.lock()
+            .unwrap_or_else(|err| panic!("AsyncClockProCache: {}", err))
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+impl<V> Flight<V> {
+    fn finish(&self, value: Option<V>) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        if let FlightState::Loading(wakers) =
+            std::mem::replace(&mut *state, FlightState::Done(value))
+        {
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+// Makes sure waiters are released if the loading future is dropped or panics
+struct LeaderGuard<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    cache: &'a AsyncClockProCache<K, V>,
+    key: &'a K,
+    flight: &'a Arc<Flight<V>>,
+}
+
+impl<'a, K, V> LeaderGuard<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn complete(self, value: V) {
+        {
+            let mut inner = self.cache.locked();
+            inner.cache.insert(self.key.clone(), value.clone());
+            inner.in_flight.remove(self.key);
+        }
+        self.flight.finish(Some(value));
+        std::mem::forget(self);
+    }
+}
+
+impl<'a, K, V> Drop for LeaderGuard<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn drop(&mut self) {
+        self.cache.locked().in_flight.remove(self.key);
+        self.flight.finish(None);
+    }
+}
+
+// Polls a loader, marking its flight as being loaded on this thread meanwhile
+struct Loading<Fut> {
+    flight: usize,
+    future: Fut,
+}
+
+impl<Fut: Future> Future for Loading<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _marker = Marker::new(&LOADING, self.flight);
+        // The future is never moved out of `self`
+        unsafe { self.map_unchecked_mut(|loading| &mut loading.future) }.poll(cx)
+    }
+}
+
+struct FlightWait<'a, V> {
+    flight: &'a Flight<V>,
+}
+
+impl<'a, V: Clone> Future for FlightWait<'a, V> {
+    type Output = Option<V>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self
+            .flight
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        match &mut *state {
+            FlightState::Done(value) => Poll::Ready(value.clone()),
+            FlightState::Loading(wakers) => {
+                if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsyncClockProCache, ReentrantAccess};
+    use crate::ClockProCache;
+    use std::future::Future;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::task::{Context, Poll, Wake};
+    use std::thread;
+    use std::time::Duration;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_flight() {
+        let cache = Arc::new(AsyncClockProCache::new(
+            ClockProCache::<u64, u64>::new(3).unwrap(),
+        ));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (cache, loads, barrier) = (cache.clone(), loads.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    block_on(cache.get_or_load(1, || async {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        42
+                    }))
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 42);
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.with_cache(|cache| cache.get(&1).copied()), Some(42));
+    }
+
+    #[test]
+    fn test_cancelled_load() {
+        let cache = AsyncClockProCache::new(ClockProCache::<u64, u64>::new(3).unwrap());
+        {
+            let mut stalled = Box::pin(cache.get_or_load(1, std::future::pending));
+            let waker = Arc::new(ThreadWaker(thread::current())).into();
+            assert!(stalled
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending());
+        }
+        assert_eq!(block_on(cache.get_or_load(1, || async { 7 })), 7);
+    }
+
+    #[test]
+    fn test_future_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<AsyncClockProCache<String, Vec<u8>>>();
+        let cache = AsyncClockProCache::new(ClockProCache::<u64, u64>::new(3).unwrap());
+        assert_send(&cache.get_or_load(1, || async { 1 }));
+    }
+
+    #[test]
+    fn test_reentrant_access() {
+        let cache = AsyncClockProCache::new(ClockProCache::<u64, u64>::new(3).unwrap());
+        let nested = cache.with_cache(|_| cache.try_with_cache(|cache| cache.len()));
+        assert_eq!(nested, Err(ReentrantAccess));
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            cache.with_cache(|_| cache.with_cache(|cache| cache.len()))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(cache.try_with_cache(|cache| cache.len()), Ok(0));
+
+        // Loading other keys from a loader is fine
+        let value = block_on(cache.get_or_load(1, || async {
+            cache.get_or_load(2, || async { 2 }).await + 1
+        }));
+        assert_eq!(value, 3);
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            block_on(cache.get_or_load(5, || cache.get_or_load(5, || async { 5 })))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(block_on(cache.get_or_load(5, || async { 6 })), 6);
+    }
+
+    #[test]
+    fn test_failed_load() {
+        let cache = AsyncClockProCache::new(ClockProCache::<u64, u64>::new(3).unwrap());
+        let failed = block_on(cache.get_or_insert_with_async(1, || async { Err("unavailable") }));
+        assert_eq!(failed, Err("unavailable"));
+        assert!(cache.with_cache(|cache| cache.is_empty()));
+
+        let loaded = block_on(cache.get_or_insert_with_async(1, || async { Ok::<_, ()>(5) }));
+        assert_eq!(loaded, Ok(5));
+        assert_eq!(cache.with_cache(|cache| cache.get(&1).copied()), Some(5));
+    }
+}
@@ -3,13 +3,62 @@ extern crate bitflags;
 
 use unsafe_unwrap::UnsafeUnwrap;
 
+#[cfg(feature = "async")]
+mod async_cache;
+
+#[cfg(feature = "async")]
+pub use crate::async_cache::{AsyncClockProCache, ReentrantAccess};
+
+use crate::bloom::BloomFilter;
 use crate::token_ring::{Token, TokenRing};
 use std::borrow::Borrow;
-use std::collections::HashMap;
-use std::hash::Hash;
+use std::collections::hash_map::{DefaultHasher, Entry, RandomState};
+use std::collections::{HashMap, TryReserveError};
+use std::fmt;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash};
 use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+// Counter arithmetic. With debug assertions or the `checked-counts` feature,
+// a counter drifting out of range panics with its name instead of wrapping.
+macro_rules! counter_add {
+    ($counter:expr) => {
+        if cfg!(any(debug_assertions, feature = "checked-counts")) {
+            $counter
+                .checked_add(1)
+                .unwrap_or_else(|| panic!("{} overflowed", stringify!($counter)))
+        } else {
+            $counter + 1
+        }
+    };
+}
+
+macro_rules! counter_sub {
+    ($counter:expr) => {
+        if cfg!(any(debug_assertions, feature = "checked-counts")) {
+            $counter
+                .checked_sub(1)
+                .unwrap_or_else(|| panic!("{} underflowed", stringify!($counter)))
+        } else {
+            $counter - 1
+        }
+    };
+}
+
+macro_rules! inc {
+    ($counter:expr) => {
+        $counter = counter_add!($counter)
+    };
+}
+
+macro_rules! dec {
+    ($counter:expr) => {
+        $counter = counter_sub!($counter)
+    };
+}
 
 bitflags! {
+    #[derive(Clone)]
     struct NodeType: u8 {
         const EMPTY     = 0b00001;
         const HOT       = 0b00010;
@@ -17,6 +66,250 @@ bitflags! {
         const TEST      = 0b01000;
         const MASK      = Self::EMPTY.bits() | Self::HOT.bits() | Self::COLD.bits() | Self::TEST.bits();
         const REFERENCE = 0b10000;
+        const PINNED    = 0b100000;
+    }
+}
+
+impl NodeType {
+    #[inline]
+    fn state(&self) -> EntryState {
+        if self.intersects(NodeType::HOT) {
+            EntryState::Hot
+        } else if self.intersects(NodeType::COLD) {
+            EntryState::Cold
+        } else {
+            debug_assert!(self.intersects(NodeType::TEST));
+            EntryState::Test
+        }
+    }
+
+    // Whether the hands may act on a node in `state`, pinned nodes being
+    // skipped
+    #[inline]
+    fn is_unpinned(&self, state: NodeType) -> bool {
+        self.intersects(state) && !self.intersects(NodeType::PINNED)
+    }
+}
+
+/// Classification of a tracked entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EntryState {
+    /// Resident and frequently accessed
+    Hot,
+    /// Resident, but recently added or demoted
+    Cold,
+    /// Ghost entry: the value was evicted, only the key is remembered
+    Test,
+}
+
+/// Outcome of `get_detailed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GetResult<T> {
+    /// The entry is resident
+    Hit(T),
+    /// The value was evicted recently, only the key is remembered
+    Ghost,
+    /// The key is not tracked at all
+    Miss,
+}
+
+/// A slot held for a key whose value is still being loaded, returned by
+/// `ClockProCache::reserve_slot`.
+///
+/// The slot stays taken until the reservation is passed to `fulfill` or to
+/// `cancel_reservation`.
+#[must_use]
+#[derive(Debug)]
+pub struct SlotReservation<K> {
+    key: K,
+    // Whether the key was a ghost when the slot was reserved
+    ghost_hit: bool,
+}
+
+impl<K> SlotReservation<K> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+/// A reference to a resident entry, returned by `ClockProCache::handle`,
+/// that `get_by_handle` follows without hashing the key.
+///
+/// The handle goes stale once the entry is evicted or removed, even if its
+/// key is inserted again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Handle {
+    token: Token,
+    generation: u64,
+}
+
+/// A change of capacity, as reported to the `on_capacity_change` callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapacityChange {
+    pub old_capacity: usize,
+    pub new_capacity: usize,
+    pub old_test_capacity: usize,
+    pub new_test_capacity: usize,
+    /// Resident entries evicted to fit the new capacity
+    pub evicted: u64,
+    /// Ghost entries dropped to fit the new test capacity
+    pub ghosts_removed: u64,
+}
+
+type CapacityListener = Box<dyn FnMut(CapacityChange) + Send + Sync>;
+
+/// Outcome of `replay_trace`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Resident entries evicted during the replay
+    pub evictions: u64,
+}
+
+impl ReplayStats {
+    /// Fraction of the accesses that were hits, or 0 for an empty trace.
+    pub fn hit_ratio(&self) -> f64 {
+        let accesses = self.hits + self.misses;
+        if accesses == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / accesses as f64
+    }
+}
+
+/// Replacement policy run by the clock hands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum EvictionPolicy {
+    /// CLOCK-Pro, with hot, cold and ghost entries
+    #[default]
+    ClockPro,
+    /// Plain CLOCK: a single hand gives referenced entries a second chance,
+    /// and no ghost entries are kept
+    Clock,
+}
+
+/// How the node storage grows, set with `slab_growth`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SlabGrowth {
+    /// Room for `capacity + test_capacity` nodes up front, then geometric
+    /// growth whenever more is needed, as with any `Vec`
+    #[default]
+    Doubling,
+    /// Room for the given number of nodes, and growth by that many nodes at a
+    /// time: more reallocations, but none of them large
+    Increment(usize),
+    /// Room for `capacity + test_capacity` nodes up front, reserved again
+    /// whenever the capacity or the test capacity grows, so that insertions
+    /// never reallocate the storage
+    Preallocate,
+}
+
+impl SlabGrowth {
+    // Room to set aside up front for a cache tracking up to `nodes` entries
+    #[inline]
+    fn initial_storage(self, nodes: usize) -> usize {
+        match self {
+            SlabGrowth::Increment(step) => step.min(nodes),
+            _ => nodes,
+        }
+    }
+
+    // Grows a full ring by the fixed step, ahead of an insertion
+    #[inline]
+    fn reserve_ring(self, ring: &mut TokenRing) {
+        if let SlabGrowth::Increment(step) = self {
+            if ring.len() == ring.capacity() {
+                ring.reserve_exact(step);
+            }
+        }
+    }
+}
+
+/// Metadata of a tracked entry, as returned by `inspect`.
+#[derive(Debug)]
+pub struct EntryInfo<'a, V> {
+    pub value: Option<&'a V>,
+    pub state: EntryState,
+    pub referenced: bool,
+}
+
+/// Snapshot of how the cache slots are used, as returned by `occupancy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Occupancy {
+    pub hot: usize,
+    pub cold: usize,
+    pub test: usize,
+    pub free_resident: usize,
+    pub free_test: usize,
+}
+
+/// What `validate_and_repair` found and fixed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Map entries that did not point to a node with their key, removed
+    pub stale_map_entries: usize,
+    /// Nodes that were missing from the map, added back to it
+    pub relinked_nodes: usize,
+    /// Nodes whose key already pointed to another node, dropped
+    pub removed_nodes: usize,
+    /// Whether the entry counts had to be recomputed
+    pub counts_fixed: bool,
+    /// Whether the ring still disagrees with the map after the repair, which
+    /// cannot be fixed in place
+    pub ring_mismatch: bool,
+}
+
+impl RepairReport {
+    /// Whether nothing was wrong.
+    pub fn is_clean(&self) -> bool {
+        *self == RepairReport::default()
+    }
+}
+
+/// Counters describing how the cache has been used, as returned by `stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups through `get`, `get_mut` or `get_with` that found a value
+    pub hits: u64,
+    /// Lookups through `get`, `get_mut` or `get_with` that found no value
+    pub misses: u64,
+    /// Hits on entries that were hot at the time of the lookup
+    pub hot_hits: u64,
+    /// Hits on entries that were cold at the time of the lookup, which give
+    /// them a chance to be promoted
+    pub cold_hits: u64,
+    /// Calls to `insert`, including updates of resident entries
+    pub inserts: u64,
+    /// Cold entries whose value was discarded, turning them into ghosts
+    pub evictions: u64,
+    /// Ghost entries forgotten by the test hand
+    pub ghost_removals: u64,
+    /// Loader calls made on misses by the `get_or_insert` family
+    #[cfg(feature = "load-timing")]
+    pub load_count: u64,
+    /// Time spent in those loader calls
+    #[cfg(feature = "load-timing")]
+    pub total_load_time: std::time::Duration,
+}
+
+impl CacheStats {
+    /// Fraction of the lookups that were misses, or 0 if there were none.
+    pub fn miss_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.misses as f64 / lookups as f64
+    }
+
+    /// Evictions per 1000 inserts, or 0 if there were none. Unlike the raw
+    /// counters, this can be compared across caches of different sizes.
+    pub fn evictions_per_1000_inserts(&self) -> f64 {
+        if self.inserts == 0 {
+            return 0.0;
+        }
+        self.evictions as f64 * 1000.0 / self.inserts as f64
     }
 }
 
@@ -24,14 +317,48 @@ struct Node<K, V> {
     key: K,
     value: Option<V>,
     node_type: NodeType,
+    // Saturating access counter, only used with `frequency_counter_limit`
+    frequency: u8,
+    #[cfg(feature = "access-count")]
+    accesses: u64,
+    // Set by `meta_link`, tells apart the nodes stored in the same slot
+    generation: u64,
     phantom_k: PhantomData<K>,
 }
 
-pub struct ClockProCache<K, V> {
+impl<K, V> Node<K, V> {
+    #[inline]
+    fn reference(&mut self, frequency_limit: Option<u8>) {
+        self.reference_by(1, frequency_limit);
+    }
+
+    #[inline]
+    fn reference_by(&mut self, bump: u8, frequency_limit: Option<u8>) {
+        self.node_type.insert(NodeType::REFERENCE);
+        if let Some(limit) = frequency_limit {
+            self.frequency = self.frequency.saturating_add(bump).min(limit);
+        }
+    }
+
+    // Consumes one reference; returns whether the node was referenced
+    #[inline]
+    fn consume_reference(&mut self) -> bool {
+        if !self.node_type.intersects(NodeType::REFERENCE) {
+            return false;
+        }
+        self.frequency = self.frequency.saturating_sub(1);
+        if self.frequency == 0 {
+            self.node_type.remove(NodeType::REFERENCE);
+        }
+        true
+    }
+}
+
+pub struct ClockProCache<K, V, S = RandomState> {
     capacity: usize,
     test_capacity: usize,
     cold_capacity: usize,
-    map: HashMap<K, Token>,
+    map: HashMap<K, Token, S>,
     ring: TokenRing,
     slab: Vec<Option<Node<K, V>>>,
     hand_hot: Token,
@@ -40,11 +367,301 @@ pub struct ClockProCache<K, V> {
     count_hot: usize,
     count_cold: usize,
     count_test: usize,
+    // Pinned entries are resident, but counted neither as hot nor as cold
+    count_pinned: usize,
+    // Keys of the outstanding `SlotReservation`s, each holding a resident slot
+    reserved: Vec<K>,
     inserted: u64,
     evicted: u64,
+    // Handed out to the nodes as they are linked
+    next_generation: u64,
+    // Next slot whose frequency counter is halved, with `aging_interval`
+    aging_cursor: usize,
+    stats: CacheStats,
+    ghost_key_bytes: usize,
+    hands_seed: Option<u64>,
+    // Set while a `BatchGuard` is alive
+    defer_evictions: bool,
+    // Insertions of non-resident keys and ghost hits among them, since the
+    // test capacity was last tuned
+    ghost_hit_window: (usize, usize),
+    // Collects the keys of evicted entries while it is `Some`
+    evicted_keys: Option<Vec<K>>,
+    bloom: Option<BloomFilter>,
+    capacity_listener: Option<CapacityListener>,
+    options: Options<K>,
+    #[cfg(feature = "cold-capacity-history")]
+    cold_capacity_history: Vec<usize>,
+    #[cfg(feature = "access-log")]
+    access_log: Vec<(K, bool)>,
     phantom_k: PhantomData<K>,
 }
 
+#[cfg(feature = "cold-capacity-history")]
+const COLD_CAPACITY_HISTORY_LEN: usize = 1024;
+
+#[cfg(feature = "access-log")]
+const ACCESS_LOG_LEN: usize = 1024;
+
+/// Approximate memory footprint of a value, used to budget ghost keys.
+pub trait ByteSized {
+    fn byte_size(&self) -> usize;
+}
+
+impl ByteSized for String {
+    fn byte_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.capacity()
+    }
+}
+
+impl ByteSized for Vec<u8> {
+    fn byte_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.capacity()
+    }
+}
+
+impl ByteSized for Box<str> {
+    fn byte_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.len()
+    }
+}
+
+macro_rules! impl_byte_sized {
+    ($($t:ty),*) => {
+        $(impl ByteSized for $t {
+            fn byte_size(&self) -> usize {
+                std::mem::size_of::<Self>()
+            }
+        })*
+    };
+}
+
+impl_byte_sized!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, char);
+
+type ByteSizeFn<K> = fn(&K) -> usize;
+
+struct Options<K> {
+    shrink_ghosts_with_capacity: bool,
+    merge_overwrites: bool,
+    max_ghost_key_memory: Option<(usize, ByteSizeFn<K>)>,
+    randomized_start: Option<u64>,
+    eviction_low_watermark: Option<usize>,
+    frequency_limit: Option<u8>,
+    eviction_policy: EvictionPolicy,
+    negative_bloom: Option<usize>,
+    adaptive_test_capacity: Option<usize>,
+    min_hot: usize,
+    ghost_hit_state: EntryState,
+    aging_interval: Option<usize>,
+    slab_growth: SlabGrowth,
+}
+
+impl<K> Default for Options<K> {
+    fn default() -> Self {
+        Options {
+            shrink_ghosts_with_capacity: false,
+            merge_overwrites: false,
+            max_ghost_key_memory: None,
+            randomized_start: None,
+            eviction_low_watermark: None,
+            frequency_limit: None,
+            eviction_policy: EvictionPolicy::ClockPro,
+            negative_bloom: None,
+            adaptive_test_capacity: None,
+            min_hot: 0,
+            ghost_hit_state: EntryState::Hot,
+            aging_interval: None,
+            slab_growth: SlabGrowth::Doubling,
+        }
+    }
+}
+
+impl<K> Clone for Options<K> {
+    fn clone(&self) -> Self {
+        Options {
+            shrink_ghosts_with_capacity: self.shrink_ghosts_with_capacity,
+            merge_overwrites: self.merge_overwrites,
+            max_ghost_key_memory: self.max_ghost_key_memory,
+            randomized_start: self.randomized_start,
+            eviction_low_watermark: self.eviction_low_watermark,
+            frequency_limit: self.frequency_limit,
+            eviction_policy: self.eviction_policy,
+            negative_bloom: self.negative_bloom,
+            adaptive_test_capacity: self.adaptive_test_capacity,
+            min_hot: self.min_hot,
+            ghost_hit_state: self.ghost_hit_state,
+            aging_interval: self.aging_interval,
+            slab_growth: self.slab_growth,
+        }
+    }
+}
+
+pub struct ClockProCacheBuilder<K, V> {
+    capacity: usize,
+    test_capacity: Option<usize>,
+    options: Options<K>,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V> ClockProCacheBuilder<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        ClockProCacheBuilder {
+            capacity,
+            test_capacity: None,
+            options: Options::default(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Number of ghost entries to keep; defaults to the cache capacity.
+    pub fn test_capacity(mut self, test_capacity: usize) -> Self {
+        self.test_capacity = Some(test_capacity);
+        self
+    }
+
+    /// When set, shrinking the cache with `set_capacity` also shrinks the test
+    /// set in the same proportion, so that the memory used by ghost keys stays
+    /// proportional to the resident budget.
+    pub fn shrink_ghosts_with_capacity(mut self, shrink: bool) -> Self {
+        self.options.shrink_ghosts_with_capacity = shrink;
+        self
+    }
+
+    /// When set, `merge` replaces the values of keys present in both caches
+    /// with the ones from the merged cache. By default the existing values win.
+    pub fn merge_overwrites(mut self, overwrite: bool) -> Self {
+        self.options.merge_overwrites = overwrite;
+        self
+    }
+
+    /// Experimental: moves the clock hands to pseudo-random positions, derived
+    /// from `seed`, when the first eviction happens. This can break up
+    /// eviction patterns correlated with the insertion order. Disabled by
+    /// default.
+    pub fn randomized_start(mut self, seed: u64) -> Self {
+        self.options.randomized_start = Some(seed);
+        self
+    }
+
+    /// Once an insertion requires an eviction, keep evicting until only
+    /// `watermark` entries, including the new one, remain resident. This
+    /// leaves headroom so that evictions happen in fewer, larger batches.
+    /// The watermark must be between 1 and the capacity. Evictions stop short
+    /// of it rather than demote hot entries beyond what the hot hand would,
+    /// for instance below `min_hot`.
+    pub fn eviction_low_watermark(mut self, watermark: usize) -> Self {
+        self.options.eviction_low_watermark = Some(watermark);
+        self
+    }
+
+    /// Keeps at least `min_hot` entries in the hot set once it has grown that
+    /// large: the hot hand stops demoting entries at that floor, whatever
+    /// the adaptive cold capacity asks for. The floor must be below the
+    /// capacity.
+    pub fn min_hot(mut self, min_hot: usize) -> Self {
+        self.options.min_hot = min_hot;
+        self
+    }
+
+    /// State of an entry inserted while its key is a ghost: `Hot`, the
+    /// default, or `Cold`, in which case the entry has to be accessed again
+    /// before the cold hand reaches it to make it into the hot set. `Test` is
+    /// rejected.
+    pub fn ghost_hit_promotes_to(mut self, state: EntryState) -> Self {
+        self.options.ghost_hit_state = state;
+        self
+    }
+
+    /// Experimental: replaces the single reference bit with a counter that
+    /// saturates at `limit`. Each access increments the counter, each pass of
+    /// the hot or cold hand over the entry decrements it, and the entry is
+    /// only demoted once it reaches zero, which favors frequently used
+    /// entries over recently used ones. The limit must be at least 1.
+    pub fn frequency_counter_limit(mut self, limit: u8) -> Self {
+        self.options.frequency_limit = Some(limit);
+        self
+    }
+
+    /// With `frequency_counter_limit`, halves the counter of every entry once
+    /// per `n` lookups, so that entries that were popular long ago do not
+    /// outlive the ones that are popular now.
+    ///
+    /// The sweep is spread over the lookups, each of them aging a slice of
+    /// the entries.
+    pub fn aging_interval(mut self, n: usize) -> Self {
+        self.options.aging_interval = Some(n);
+        self
+    }
+
+    /// How the node storage and the ring grow; defaults to
+    /// `SlabGrowth::Doubling`. The map keeps growing in its own steps, except
+    /// with `SlabGrowth::Preallocate`.
+    pub fn slab_growth(mut self, growth: SlabGrowth) -> Self {
+        self.options.slab_growth = growth;
+        self
+    }
+
+    /// Replacement policy; defaults to CLOCK-Pro. The storage and the API are
+    /// the same for every policy, so policies can be compared on identical
+    /// workloads.
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.options.eviction_policy = policy;
+        self
+    }
+
+    /// Maintains a Bloom filter of the tracked keys, sized for about
+    /// `expected_items` keys, and checks it before the map on every lookup.
+    ///
+    /// Lookups for keys that were never inserted then usually skip the map
+    /// probe entirely, at the cost of an extra hash for the other lookups and
+    /// the insertions. False positives simply fall through to the map. Since
+    /// bits cannot be cleared, removed and forgotten keys keep matching until
+    /// the filter is rebuilt from the tracked keys, which happens once twice
+    /// `expected_items` keys have been added to it.
+    pub fn negative_bloom(mut self, expected_items: usize) -> Self {
+        self.options.negative_bloom = Some(expected_items);
+        self
+    }
+
+    /// Lets the cache tune its test capacity, up to `max_test_capacity`.
+    ///
+    /// After every `capacity` insertions of non-resident keys, the test
+    /// capacity grows by a quarter if more than 1/16 of them were ghost hits,
+    /// and shrinks by an eighth, down to a quarter of the capacity, if fewer
+    /// than 1/128 were. The initial value is the one set with
+    /// `test_capacity`, and must not exceed `max_test_capacity`.
+    pub fn adaptive_test_capacity(mut self, max_test_capacity: usize) -> Self {
+        self.options.adaptive_test_capacity = Some(max_test_capacity);
+        self
+    }
+
+    pub fn build(self) -> Result<ClockProCache<K, V>, &'static str> {
+        let test_capacity = self.test_capacity.unwrap_or(self.capacity);
+        ClockProCache::with_options(
+            self.capacity,
+            test_capacity,
+            self.options,
+            RandomState::new(),
+        )
+    }
+}
+
+impl<K, V> ClockProCacheBuilder<K, V>
+where
+    K: Eq + Hash + Clone + ByteSized,
+{
+    /// Caps the memory used by the keys of ghost entries, as measured by
+    /// `ByteSized`. The oldest ghosts are dropped once the budget is exceeded,
+    /// even if the test set has not reached its capacity.
+    pub fn max_ghost_key_memory(mut self, bytes: usize) -> Self {
+        self.options.max_ghost_key_memory = Some((bytes, K::byte_size));
+        self
+    }
+}
+
 impl<K, V> ClockProCache<K, V>
 where
     K: Eq + Hash + Clone,
@@ -57,490 +674,5324 @@ where
         capacity: usize,
         test_capacity: usize,
     ) -> Result<Self, &'static str> {
+        Self::with_options(
+            capacity,
+            test_capacity,
+            Options::default(),
+            RandomState::new(),
+        )
+    }
+
+    /// Creates a cache whose test capacity is `ratio` times its capacity,
+    /// rounded, with at least one ghost entry.
+    pub fn with_test_ratio(capacity: usize, ratio: f64) -> Result<Self, &'static str> {
+        if !ratio.is_finite() || ratio < 0.0 {
+            return Err("The test ratio must be a finite, non-negative number");
+        }
+        let test_capacity = ((capacity as f64 * ratio).round() as usize).max(1);
+        Self::new_with_test_capacity(capacity, test_capacity)
+    }
+
+    /// Creates a cache with a test capacity picked from its capacity.
+    ///
+    /// Small caches get as many ghost entries as resident ones, like `new`.
+    /// Past 4096 entries, the test set only grows by one ghost for every 8
+    /// additional resident entries, to bound the memory used by ghost keys in
+    /// very large caches. See `auto_test_capacity`.
+    pub fn auto(capacity: usize) -> Result<Self, &'static str> {
+        Self::new_with_test_capacity(capacity, auto_test_capacity(capacity))
+    }
+
+    /// Creates a cache and inserts the pairs of `iter` into it, in order.
+    ///
+    /// Meant for migrating data from another cache: the pairs go through
+    /// `insert`, so if there are more than `capacity` of them, the later ones
+    /// can evict the earlier ones. See `export_pairs` for the other way.
+    pub fn from_pairs_iter<I>(capacity: usize, iter: I) -> Result<Self, &'static str>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut cache = Self::new(capacity)?;
+        for (key, value) in iter {
+            cache.insert(key, value);
+        }
+        Ok(cache)
+    }
+
+    pub fn builder(capacity: usize) -> ClockProCacheBuilder<K, V> {
+        ClockProCacheBuilder::new(capacity)
+    }
+
+    /// Creates a cache that never stores anything.
+    ///
+    /// `insert` is a no-op that returns `false`, and every lookup misses. This
+    /// is also what `new(0)` returns, so caching can be turned off through
+    /// configuration while keeping a single code path; capacities of 1 and 2
+    /// are still rejected.
+    pub fn disabled() -> Self {
+        Self::disabled_with_hasher(RandomState::new())
+    }
+}
+
+/// A cache storing its values behind a `Box`.
+///
+/// Nodes are stored inline in a `Vec` that grows with the number of tracked
+/// entries, ghost slots included. With large values, boxing them keeps that
+/// storage small, and cheap to grow as entries are added, at the cost
+/// of an allocation per insertion and an indirection per lookup.
+pub type BoxedClockProCache<K, V, S = RandomState> = ClockProCache<K, Box<V>, S>;
+
+/// A hasher without random keys, used by `ClockProCache::deterministic`.
+pub type DeterministicState = BuildHasherDefault<DefaultHasher>;
+
+impl<K, V> ClockProCache<K, V, DeterministicState>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Creates a cache whose keys are hashed with fixed keys, so that runs
+    /// with the same operations behave identically, which makes tests and
+    /// benchmarks reproducible.
+    ///
+    /// The hash function can be predicted, so this is not suitable for keys
+    /// controlled by an adversary. It is also only stable for a given Rust
+    /// version.
+    pub fn deterministic(capacity: usize) -> Result<Self, &'static str> {
+        Self::with_hasher(capacity, DeterministicState::default())
+    }
+}
+
+impl<K, V, S> ClockProCache<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Same as `new`, hashing keys with `hasher`.
+    pub fn with_hasher(capacity: usize, hasher: S) -> Result<Self, &'static str> {
+        Self::with_options(capacity, capacity, Options::default(), hasher)
+    }
+
+    fn with_options(
+        capacity: usize,
+        test_capacity: usize,
+        options: Options<K>,
+        hasher: S,
+    ) -> Result<Self, &'static str> {
+        if capacity == 0 {
+            return Ok(Self::disabled_with_hasher(hasher));
+        }
         if capacity < 3 {
             return Err("Cache size cannot be less than 3 entries");
         }
-        let mut slab = Vec::with_capacity(capacity + test_capacity);
-        for _ in 0..capacity + test_capacity {
-            slab.push(None);
+        if capacity.checked_add(test_capacity).is_none() {
+            return Err("The cache cannot track that many entries and ghosts");
+        }
+        if let Some(watermark) = options.eviction_low_watermark {
+            if watermark == 0 || watermark > capacity {
+                return Err("The eviction low watermark must be between 1 and the capacity");
+            }
+        }
+        if options
+            .adaptive_test_capacity
+            .is_some_and(|max| max < test_capacity)
+        {
+            return Err("The adaptive test capacity bound cannot be below the test capacity");
+        }
+        if options.ghost_hit_state == EntryState::Test {
+            return Err("A ghost hit must bring the entry back as hot or cold");
+        }
+        if options.min_hot >= capacity {
+            return Err("The minimum number of hot entries must be below the capacity");
+        }
+        if options.frequency_limit == Some(0) {
+            return Err("The frequency counter limit must be at least 1");
+        }
+        if options.slab_growth == SlabGrowth::Increment(0) {
+            return Err("The slab growth increment must be at least 1");
+        }
+        match options.aging_interval {
+            Some(0) => return Err("The aging interval must be at least 1"),
+            Some(_) if options.frequency_limit.is_none() => {
+                return Err("Aging requires a frequency counter limit")
+            }
+            _ => {}
+        }
+        Ok(Self::from_options(capacity, test_capacity, options, hasher))
+    }
+
+    // Builds a cache from options that are known to be valid, or that come
+    // from an existing cache, whose capacities may have moved since they
+    // were checked
+    fn from_options(capacity: usize, test_capacity: usize, options: Options<K>, hasher: S) -> Self {
+        if capacity == 0 {
+            return Self::disabled_with_hasher(hasher);
         }
-        let cache = ClockProCache {
+        let storage = options
+            .slab_growth
+            .initial_storage(capacity + test_capacity);
+        ClockProCache {
             capacity,
             test_capacity,
-            cold_capacity: capacity,
-            map: HashMap::with_capacity(capacity + test_capacity),
-            ring: TokenRing::with_capacity(capacity + test_capacity),
-            slab,
+            cold_capacity: capacity - 1,
+            map: HashMap::with_capacity_and_hasher(storage, hasher),
+            ring: TokenRing::with_capacity(storage),
+            slab: Vec::with_capacity(storage),
             hand_hot: 0,
             hand_cold: 0,
             hand_test: 0,
             count_hot: 0,
             count_cold: 0,
             count_test: 0,
+            count_pinned: 0,
+            reserved: Vec::new(),
             inserted: 0,
+            next_generation: 0,
+            aging_cursor: 0,
             evicted: 0,
+            stats: CacheStats::default(),
+            ghost_key_bytes: 0,
+            hands_seed: options.randomized_start,
+            defer_evictions: false,
+            evicted_keys: None,
+            ghost_hit_window: (0, 0),
+            bloom: options.negative_bloom.map(BloomFilter::new),
+            capacity_listener: None,
+            options,
+            #[cfg(feature = "cold-capacity-history")]
+            cold_capacity_history: vec![capacity - 1],
+            #[cfg(feature = "access-log")]
+            access_log: Vec::new(),
             phantom_k: PhantomData,
-        };
-        Ok(cache)
+        }
     }
 
-    #[inline]
-    pub fn len(&self) -> usize {
-        self.count_cold + self.count_hot
+    fn disabled_with_hasher(hasher: S) -> Self {
+        ClockProCache {
+            capacity: 0,
+            test_capacity: 0,
+            cold_capacity: 0,
+            map: HashMap::with_hasher(hasher),
+            ring: TokenRing::with_capacity(1),
+            slab: Vec::new(),
+            hand_hot: 0,
+            hand_cold: 0,
+            hand_test: 0,
+            count_hot: 0,
+            count_cold: 0,
+            count_test: 0,
+            count_pinned: 0,
+            reserved: Vec::new(),
+            inserted: 0,
+            next_generation: 0,
+            aging_cursor: 0,
+            evicted: 0,
+            stats: CacheStats::default(),
+            ghost_key_bytes: 0,
+            hands_seed: None,
+            defer_evictions: false,
+            evicted_keys: None,
+            ghost_hit_window: (0, 0),
+            bloom: None,
+            capacity_listener: None,
+            options: Options::default(),
+            #[cfg(feature = "cold-capacity-history")]
+            cold_capacity_history: Vec::new(),
+            #[cfg(feature = "access-log")]
+            access_log: Vec::new(),
+            phantom_k: PhantomData,
+        }
     }
 
     #[inline]
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    pub fn is_disabled(&self) -> bool {
+        self.capacity == 0
     }
 
     #[inline]
-    pub fn recent_len(&self) -> usize {
-        self.count_cold
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
+    /// Number of ghost entries the cache can remember. With
+    /// `adaptive_test_capacity`, this is the value currently chosen by the
+    /// cache.
     #[inline]
-    pub fn frequent_len(&self) -> usize {
-        self.count_hot
+    pub fn test_capacity(&self) -> usize {
+        self.test_capacity
     }
 
-    #[inline]
-    pub fn test_len(&self) -> usize {
-        self.count_test
+    /// Changes the number of resident entries the cache can hold.
+    ///
+    /// Shrinking runs the clock hands until the resident entries fit. If the
+    /// cache was built with `shrink_ghosts_with_capacity`, the test capacity
+    /// is scaled down by the same ratio and excess ghosts are dropped.
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<(), &'static str> {
+        let before = self.capacity_snapshot();
+        self.resize(capacity)?;
+        self.notify_capacity_change(before);
+        Ok(())
     }
 
-    #[inline]
-    pub fn inserted(&self) -> u64 {
-        self.inserted
+    /// Changes the number of ghost entries the cache can remember, dropping
+    /// the oldest ones if there are too many.
+    pub fn set_test_capacity(&mut self, test_capacity: usize) -> Result<(), &'static str> {
+        if self.is_disabled() {
+            return Err("A disabled cache cannot be resized");
+        }
+        let before = self.capacity_snapshot();
+        self.test_capacity = test_capacity;
+        self.drop_excess_ghosts();
+        self.preallocate_storage();
+        self.notify_capacity_change(before);
+        Ok(())
     }
 
-    #[inline]
-    pub fn evicted(&self) -> u64 {
-        self.evicted
+    /// Reserves room for at least `additional` more tracked entries in the
+    /// map, the node storage and the ring.
+    pub fn reserve(&mut self, additional: usize) {
+        self.map.reserve(additional);
+        self.slab.reserve(additional);
+        self.ring.reserve(additional);
     }
 
-    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        let token = match self.map.get(key) {
-            None => return None,
-            Some(&token) => token,
-        };
-        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
-        node.value.as_ref()?;
-        node.node_type.insert(NodeType::REFERENCE);
-        Some(node.value.as_mut().unwrap())
+    /// Same as `reserve`, without the slack that amortizes repeated growth,
+    /// for when the final number of tracked entries is known.
+    ///
+    /// The node storage and the ring get exactly the requested room. The map
+    /// has no exact mode and still rounds its table up to its own bucket
+    /// sizes.
+    pub fn reserve_exact(&mut self, additional: usize) {
+        self.map.reserve(additional);
+        self.slab.reserve_exact(additional);
+        self.ring.reserve_exact(additional);
     }
 
-    pub fn get<Q: ?Sized>(&mut self, key: &Q) -> Option<&V>
-    where
-        Q: Hash + Eq,
-        K: Borrow<Q>,
-    {
-        let token = match self.map.get(key) {
-            None => return None,
-            Some(&token) => token,
-        };
-        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
-        node.value.as_ref()?;
-        node.node_type.insert(NodeType::REFERENCE);
-        Some(node.value.as_ref().unwrap())
+    /// Tries to reserve room for `additional` more tracked entries, returning
+    /// an error instead of aborting if the allocation fails.
+    ///
+    /// This covers the map and the node storage. The ring linking the nodes
+    /// together only takes two words per entry and is grown with the regular,
+    /// infallible allocator.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.map.try_reserve(additional)?;
+        self.slab.try_reserve(additional)?;
+        self.ring.reserve(additional);
+        Ok(())
     }
 
-    pub fn contains_key<Q: ?Sized>(&mut self, key: &Q) -> bool
+    /// Registers a callback invoked after every change of capacity made with
+    /// `set_capacity`, `set_test_capacity` or `rebuild_with_capacity`.
+    pub fn on_capacity_change<F>(&mut self, callback: F)
     where
-        Q: Hash + Eq,
-        K: Borrow<Q>,
+        F: FnMut(CapacityChange) + Send + Sync + 'static,
     {
-        let token = match self.map.get(key) {
-            None => return false,
-            Some(&token) => token,
+        self.capacity_listener = Some(Box::new(callback));
+    }
+
+    fn resize(&mut self, capacity: usize) -> Result<(), &'static str> {
+        if self.is_disabled() {
+            return Err("A disabled cache cannot be resized");
+        }
+        if capacity < 3 {
+            return Err("Cache size cannot be less than 3 entries");
+        }
+        if capacity < self.count_pinned + self.reserved.len() + self.min_clock_capacity() {
+            return Err("The capacity must leave room for an unpinned entry");
+        }
+        if self.options.shrink_ghosts_with_capacity && capacity < self.capacity {
+            self.test_capacity =
+                (self.test_capacity as u128 * capacity as u128 / self.capacity as u128) as usize;
+        }
+        self.capacity = capacity;
+        if self.cold_capacity > capacity - 1 {
+            self.set_cold_capacity(capacity - 1);
+        }
+        self.evict_excess();
+        self.drop_excess_ghosts();
+        self.preallocate_storage();
+        Ok(())
+    }
+
+    // With `SlabGrowth::Preallocate`, makes room for as many nodes as the
+    // cache can track, so that insertions never have to grow the storage
+    fn preallocate_storage(&mut self) {
+        if self.options.slab_growth != SlabGrowth::Preallocate {
+            return;
+        }
+        let total = self.capacity + self.test_capacity;
+        self.map.reserve(total.saturating_sub(self.map.len()));
+        self.slab
+            .reserve_exact(total.saturating_sub(self.slab.len()));
+        self.ring
+            .reserve_exact(total.saturating_sub(self.ring.len()));
+    }
+
+    fn capacity_snapshot(&self) -> (usize, usize, CacheStats) {
+        (self.capacity, self.test_capacity, self.stats)
+    }
+
+    fn notify_capacity_change(&mut self, before: (usize, usize, CacheStats)) {
+        let (old_capacity, old_test_capacity, stats) = before;
+        if let Some(callback) = self.capacity_listener.as_mut() {
+            callback(CapacityChange {
+                old_capacity,
+                new_capacity: self.capacity,
+                old_test_capacity,
+                new_test_capacity: self.test_capacity,
+                evicted: self.stats.evictions - stats.evictions,
+                ghosts_removed: self.stats.ghost_removals - stats.ghost_removals,
+            });
+        }
+    }
+
+    /// Same as `set_capacity`, but when the resident entries don't fit in the
+    /// new capacity, the ones to discard are chosen by value instead of by
+    /// the position of the clock hands.
+    ///
+    /// Cold entries go first, then hot ones, and within each set the entries
+    /// that were not referenced since the last sweep go before the others.
+    /// Discarded entries are forgotten, not turned into ghosts.
+    pub fn rebuild_with_capacity(&mut self, capacity: usize) -> Result<(), &'static str> {
+        if self.is_disabled() {
+            return Err("A disabled cache cannot be resized");
+        }
+        if capacity < 3 {
+            return Err("Cache size cannot be less than 3 entries");
+        }
+        if capacity < self.count_pinned + self.reserved.len() + self.min_clock_capacity() {
+            return Err("The capacity must leave room for an unpinned entry");
+        }
+        let before = self.capacity_snapshot();
+        let excess = self.len().saturating_sub(capacity);
+        if excess > 0 {
+            let mut ranked: Vec<(u8, Token)> = self
+                .slab
+                .iter()
+                .enumerate()
+                .filter_map(|(token, node)| {
+                    let node = node.as_ref()?;
+                    node.value.as_ref()?;
+                    if node.node_type.intersects(NodeType::PINNED) {
+                        return None;
+                    }
+                    let hot = node.node_type.intersects(NodeType::HOT) as u8;
+                    let referenced = node.node_type.intersects(NodeType::REFERENCE) as u8;
+                    Some((hot * 2 + referenced, token))
+                })
+                .collect();
+            ranked.sort_by_key(|&(rank, _)| rank);
+            for &(_, token) in &ranked[..excess] {
+                self.remove_token(token);
+                self.stats.evictions += 1;
+            }
+        }
+        self.resize(capacity)?;
+        self.notify_capacity_change(before);
+        Ok(())
+    }
+
+    /// The most recent values of the adaptive cold capacity, oldest first.
+    ///
+    /// A value is recorded every time the target size of the cold set
+    /// changes. Only the last 1024 values are guaranteed to be kept.
+    #[cfg(feature = "cold-capacity-history")]
+    pub fn cold_capacity_history(&self) -> &[usize] {
+        let history = &self.cold_capacity_history;
+        &history[history.len().saturating_sub(COLD_CAPACITY_HISTORY_LEN)..]
+    }
+
+    /// The most recent accesses, oldest first, as keys and whether they were
+    /// hits, to capture a trace that can be replayed with `replay_trace`.
+    ///
+    /// Lookups of resident and ghost entries are recorded when they happen.
+    /// Keys that are not tracked at all cannot be recorded by a lookup, which
+    /// only has a borrowed form of the key, so their miss is recorded when
+    /// they are inserted. Only the last 1024 accesses are guaranteed to be
+    /// kept.
+    #[cfg(feature = "access-log")]
+    pub fn recent_accesses(&self) -> &[(K, bool)] {
+        let log = &self.access_log;
+        &log[log.len().saturating_sub(ACCESS_LOG_LEN)..]
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.count_cold + self.count_hot + self.count_pinned
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn recent_len(&self) -> usize {
+        self.count_cold
+    }
+
+    #[inline]
+    pub fn frequent_len(&self) -> usize {
+        self.count_hot
+    }
+
+    #[inline]
+    pub fn test_len(&self) -> usize {
+        self.count_test
+    }
+
+    /// Number of nodes linked in the clock ring, which always equals
+    /// `total_tracked`.
+    #[inline]
+    pub fn ring_len(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Number of nodes the ring can link without reallocating.
+    ///
+    /// The ring and the node storage start with room for `capacity +
+    /// test_capacity` nodes, and grow on demand past that, so this is a
+    /// measure of the memory in use rather than a limit.
+    #[inline]
+    pub fn ring_capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    /// Checks the internal bookkeeping against the stored nodes, and fixes
+    /// what can be fixed.
+    ///
+    /// Map entries pointing to the wrong node are dropped, nodes missing from
+    /// the map are added back, and the entry counts are recomputed from the
+    /// node states. This is meant as a safety valve and a testing tool; on a
+    /// healthy cache it changes nothing and returns a clean report.
+    pub fn validate_and_repair(&mut self) -> RepairReport {
+        let mut report = RepairReport::default();
+        fn live<K, V>(slab: &[Option<Node<K, V>>], token: Token) -> Option<&Node<K, V>> {
+            match slab.get(token) {
+                Some(Some(node)) if !node.node_type.intersects(NodeType::EMPTY) => Some(node),
+                _ => None,
+            }
+        }
+        let before = self.map.len();
+        let slab = &self.slab;
+        self.map
+            .retain(|key, token| live(slab, *token).is_some_and(|node| node.key == *key));
+        report.stale_map_entries = before - self.map.len();
+
+        for token in 0..self.slab.len() {
+            let key = match live(&self.slab, token) {
+                Some(node) if self.map.get(&node.key) != Some(&token) => node.key.clone(),
+                _ => continue,
+            };
+            if let Entry::Vacant(entry) = self.map.entry(key) {
+                entry.insert(token);
+                report.relinked_nodes += 1;
+            } else {
+                self.meta_unlink(token);
+                report.removed_nodes += 1;
+            }
+        }
+
+        let (mut hot, mut cold, mut test, mut pinned, mut ghost_key_bytes) = (0, 0, 0, 0, 0);
+        for &token in self.map.values() {
+            let node = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+            if node.node_type.intersects(NodeType::PINNED) {
+                pinned += 1;
+            } else if node.node_type.intersects(NodeType::HOT) {
+                hot += 1;
+            } else if node.node_type.intersects(NodeType::COLD) {
+                cold += 1;
+            } else {
+                test += 1;
+                if let Some((_, byte_size)) = self.options.max_ghost_key_memory {
+                    ghost_key_bytes += byte_size(&node.key);
+                }
+            }
+        }
+        let counts = (hot, cold, test, pinned, ghost_key_bytes);
+        if counts
+            != (
+                self.count_hot,
+                self.count_cold,
+                self.count_test,
+                self.count_pinned,
+                self.ghost_key_bytes,
+            )
+        {
+            self.count_hot = hot;
+            self.count_cold = cold;
+            self.count_test = test;
+            self.count_pinned = pinned;
+            self.ghost_key_bytes = ghost_key_bytes;
+            report.counts_fixed = true;
+        }
+        report.ring_mismatch = self.ring.len() != self.map.len();
+        report
+    }
+
+    /// Number of tracked keys, resident and ghost entries alike.
+    pub fn total_tracked(&self) -> usize {
+        let tracked = self.len() + self.count_test;
+        debug_assert_eq!(
+            tracked,
+            self.ring.len(),
+            "The counts and the ring are out of sync"
+        );
+        tracked
+    }
+
+    pub fn occupancy(&self) -> Occupancy {
+        Occupancy {
+            hot: self.count_hot,
+            cold: self.count_cold,
+            test: self.count_test,
+            free_resident: self.capacity.saturating_sub(self.len()),
+            free_test: self.test_capacity.saturating_sub(self.count_test),
+        }
+    }
+
+    #[inline]
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    #[inline]
+    pub fn inserted(&self) -> u64 {
+        self.inserted
+    }
+
+    #[inline]
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let token = self.access(key)?;
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        node.reference(self.options.frequency_limit);
+        node.value.as_mut()
+    }
+
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        self.get_with(key, true)
+    }
+
+    /// Same as `get`, also returning the key stored in the cache.
+    pub fn get_key_value<Q>(&mut self, key: &Q) -> Option<(&K, &V)>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = self.access(key)?;
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        node.reference(self.options.frequency_limit);
+        Some((&node.key, node.value.as_ref()?))
+    }
+
+    /// Looks up `key` like `get`, telling a ghost entry apart from a key that
+    /// is not tracked at all.
+    pub fn get_detailed<Q>(&mut self, key: &Q) -> GetResult<&V>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = match self.access(key) {
+            Some(token) => token,
+            None if self.token_for(key).is_some() => return GetResult::Ghost,
+            None => return GetResult::Miss,
+        };
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        node.reference(self.options.frequency_limit);
+        GetResult::Hit(unsafe { node.value.as_ref().unsafe_unwrap() })
+    }
+
+    /// Looks up `key` like `get`, also telling whether the next pass of the
+    /// cold hand over the entry will promote it to the hot set.
+    ///
+    /// That is the case for cold entries, which are now referenced, unless
+    /// they are pinned or the cache runs plain CLOCK.
+    pub fn get_reporting_promotion<Q>(&mut self, key: &Q) -> Option<(&V, bool)>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = self.access(key)?;
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        node.reference(self.options.frequency_limit);
+        let promotes = self.options.eviction_policy == EvictionPolicy::ClockPro
+            && node.node_type.is_unpinned(NodeType::COLD);
+        Some((node.value.as_ref()?, promotes))
+    }
+
+    /// Looks up `key`, setting its reference bit only if `promote` is true.
+    ///
+    /// With `promote == false` the lookup leaves the eviction state untouched,
+    /// which keeps scans and prefetches from making entries look hot.
+    pub fn get_with<Q>(&mut self, key: &Q, promote: bool) -> Option<&V>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = self.access(key)?;
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        if promote {
+            node.reference(self.options.frequency_limit);
+        }
+        node.value.as_ref()
+    }
+
+    /// Returns a handle to the resident entry for `key`, without touching
+    /// the eviction state.
+    pub fn handle<Q>(&self, key: &Q) -> Option<Handle>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = self.token_for(key)?;
+        let node = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+        node.value.as_ref()?;
+        Some(Handle {
+            token,
+            generation: node.generation,
+        })
+    }
+
+    /// Same as `get_mut`, for the entry `handle` refers to. Returns `None`
+    /// if the handle is stale.
+    pub fn get_by_handle(&mut self, handle: Handle) -> Option<&mut V> {
+        match self.slab.get(handle.token) {
+            Some(Some(node)) if node.generation == handle.generation && node.value.is_some() => {}
+            _ => return None,
+        }
+        self.age_frequencies();
+        self.record_hit(handle.token);
+        let node = unsafe { self.slab[handle.token].as_mut().unsafe_unwrap() };
+        node.reference(self.options.frequency_limit);
+        node.value.as_mut()
+    }
+
+    /// Looks up `key`, adding `bump` to its frequency counter instead of 1.
+    ///
+    /// The counter still saturates at the limit set with
+    /// `frequency_counter_limit`; without a limit, any non-zero `bump` only
+    /// sets the reference bit. A `bump` of 0 leaves the entry untouched, like
+    /// `get_with(key, false)`.
+    pub fn get_weighted<Q>(&mut self, key: &Q, bump: u8) -> Option<&V>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = self.access(key)?;
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        if bump > 0 {
+            node.reference_by(bump, self.options.frequency_limit);
+        }
+        node.value.as_ref()
+    }
+
+    /// Returns a guard over the value for `key` that only sets the reference
+    /// bit if the value is actually written through it.
+    ///
+    /// Reading through the guard, even mutably borrowed, leaves the eviction
+    /// state untouched; any `DerefMut` access counts as a write.
+    pub fn peek_mut_guard<Q>(&mut self, key: &Q) -> Option<PeekMutGuard<'_, K, V>>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = self.token_for(key)?;
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        node.value.as_ref()?;
+        Some(PeekMutGuard {
+            node,
+            frequency_limit: self.options.frequency_limit,
+            dirty: false,
+        })
+    }
+
+    /// Returns the value for `key` only if `pred` accepts it.
+    ///
+    /// The reference bit is only set when the value is returned, so rejecting
+    /// a stale value does not make the entry look recently used.
+    pub fn get_if<Q, F>(&mut self, key: &Q, pred: F) -> Option<&V>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+        F: FnOnce(&V) -> bool,
+    {
+        let token = self.token_for(key)?;
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        if !pred(node.value.as_ref()?) {
+            return None;
+        }
+        node.reference(self.options.frequency_limit);
+        node.value.as_ref()
+    }
+
+    /// Returns the value and metadata of a tracked entry, without touching
+    /// its reference bit.
+    pub fn inspect<Q>(&self, key: &Q) -> Option<EntryInfo<'_, V>>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = self.token_for(key)?;
+        let node = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+        Some(EntryInfo {
+            value: node.value.as_ref(),
+            state: node.node_type.state(),
+            referenced: node.node_type.intersects(NodeType::REFERENCE),
+        })
+    }
+
+    /// Number of lookups that found the resident entry for `key` since it
+    /// was inserted, or `None` if `key` is not resident.
+    #[cfg(feature = "access-count")]
+    pub fn access_count<Q>(&self, key: &Q) -> Option<u64>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = self.token_for(key)?;
+        let node = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+        node.value.as_ref()?;
+        Some(node.accesses)
+    }
+
+    /// Whether the resident entry for `key` has not been looked up since it
+    /// was inserted, or `None` if `key` is not resident.
+    ///
+    /// Such one-hit wonders, typically left behind by scans, are the first
+    /// candidates for eviction.
+    #[cfg(feature = "access-count")]
+    pub fn is_single_access<Q>(&self, key: &Q) -> Option<bool>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        self.access_count(key).map(|accesses| accesses == 0)
+    }
+
+    pub fn contains_key<Q>(&mut self, key: &Q) -> bool
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        let token = match self.token_for(key) {
+            None => return false,
+            Some(token) => token,
         };
         unsafe { self.slab[token].as_ref().unsafe_unwrap().value.is_some() }
     }
 
+    /// Tells, for each of `keys` in order, whether it has a resident value.
+    ///
+    /// This only needs a shared reference, so it works behind an `RwLock`
+    /// read guard, for instance.
+    pub fn contains_all<Q>(&self, keys: &[&Q]) -> Vec<bool>
+    where
+        Q: ?Sized + Hash + Eq,
+        K: Borrow<Q>,
+    {
+        keys.iter()
+            .map(|key| self.resident_value(*key).is_some())
+            .collect()
+    }
+
     pub fn insert(&mut self, key: K, value: V) -> bool {
-        let token = match self.map.get(&key).cloned() {
-            None => {
+        if self.is_disabled() {
+            return false;
+        }
+        self.stats.inserts += 1;
+        // The key is hashed once through the entry API; a second hash is only
+        // needed when an eviction has to run before the new node is linked.
+        let has_room = self.count_hot + self.count_cold < self.clock_capacity();
+        let (key, token) = match self.map.entry(key) {
+            Entry::Vacant(entry) if has_room => {
+                self.options.slab_growth.reserve_ring(&mut self.ring);
+                let token = self.ring.insert_after(self.hand_hot);
+                let key = entry.key().clone();
+                entry.insert(token);
+                self.bloom_insert(&key);
+                #[cfg(feature = "access-log")]
+                self.log_access(key.clone(), false);
+                let node = Node {
+                    key,
+                    value: Some(value),
+                    node_type: NodeType::COLD,
+                    frequency: 0,
+                    #[cfg(feature = "access-count")]
+                    accesses: 0,
+                    generation: 0,
+                    phantom_k: PhantomData,
+                };
+                self.meta_link(token, node);
+                inc!(self.count_cold);
+                self.inserted += 1;
+                self.tune_test_capacity(false);
+                return true;
+            }
+            Entry::Vacant(entry) => {
+                let key = entry.into_key();
+                #[cfg(feature = "access-log")]
+                self.log_access(key.clone(), false);
                 let node = Node {
                     key,
                     value: Some(value),
                     node_type: NodeType::COLD,
+                    frequency: 0,
+                    #[cfg(feature = "access-count")]
+                    accesses: 0,
+                    generation: 0,
                     phantom_k: PhantomData,
                 };
                 self.meta_add(node);
-                self.count_cold += 1;
+                inc!(self.count_cold);
                 self.inserted += 1;
+                self.tune_test_capacity(false);
                 return true;
             }
-            Some(token) => token,
-        };
-        {
-            let mentry = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
-            if mentry.value.is_some() {
-                mentry.value = Some(value);
-                mentry.node_type.insert(NodeType::REFERENCE);
-                return false;
+            Entry::Occupied(entry) => {
+                let token = *entry.get();
+                let mentry = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+                if mentry.value.is_some() {
+                    mentry.value = Some(value);
+                    mentry.reference(self.options.frequency_limit);
+                    return false;
+                }
+                entry.remove_entry()
             }
+        };
+        self.grow_cold_capacity();
+        dec!(self.count_test);
+        self.meta_unlink(token);
+        self.link_ghost_hit(key, value);
+        true
+    }
+
+    // A ghost hit means the cold set was too small to keep the entry
+    fn grow_cold_capacity(&mut self) {
+        // Leave at least one slot to the hot set
+        if self.cold_capacity < self.capacity - 1 {
+            self.set_cold_capacity(counter_add!(self.cold_capacity));
         }
-        if self.cold_capacity < self.capacity {
-            self.cold_capacity += 1;
-        }
-        self.count_test -= 1;
-        self.meta_del(token);
+    }
+
+    // Links the new node for a key that has just left the test set
+    fn link_ghost_hit(&mut self, key: K, value: V) {
+        let hot = self.options.ghost_hit_state == EntryState::Hot;
         let node = Node {
             key,
             value: Some(value),
-            node_type: NodeType::HOT,
+            node_type: if hot { NodeType::HOT } else { NodeType::COLD },
+            frequency: 0,
+            #[cfg(feature = "access-count")]
+            accesses: 0,
+            generation: 0,
             phantom_k: PhantomData,
         };
         self.meta_add(node);
-        self.count_hot += 1;
-        true
+        if hot {
+            inc!(self.count_hot);
+        } else {
+            inc!(self.count_cold);
+        }
+        self.tune_test_capacity(true);
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
-    where
-        K: Borrow<Q>,
-        Q: Eq + Hash,
-    {
-        let token = match self.map.get(key) {
-            None => return None,
-            Some(&token) => token,
+    fn tune_test_capacity(&mut self, ghost_hit: bool) {
+        let max = match self.options.adaptive_test_capacity {
+            None => return,
+            Some(max) => max,
         };
-
-        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
-        let value = node.value.take();
-
-        // The key is in map, so the node must be HOT or COLD
-        if node.node_type.intersects(NodeType::HOT) {
-            self.count_hot -= 1;
-        } else if node.node_type.intersects(NodeType::COLD) {
-            self.count_cold -= 1;
+        self.ghost_hit_window.0 += 1;
+        self.ghost_hit_window.1 += ghost_hit as usize;
+        if self.ghost_hit_window.0 < self.capacity {
+            return;
         }
+        let (inserts, ghost_hits) = std::mem::take(&mut self.ghost_hit_window);
+        if ghost_hits * 16 > inserts {
+            self.test_capacity = (self.test_capacity + (self.test_capacity / 4).max(1)).min(max);
+            self.preallocate_storage();
+        } else if ghost_hits * 128 < inserts {
+            let min = (self.capacity / 4).max(1).min(max);
+            self.test_capacity = self
+                .test_capacity
+                .saturating_sub((self.test_capacity / 8).max(1))
+                .max(min);
+            // The excess ghosts are left to the next eviction: the test hand
+            // may have to move the cold hand, which could evict the entry
+            // being inserted
+        }
+    }
 
-        self.meta_del(token);
+    // Runs the loader of a miss, timing it with the `load-timing` feature
+    #[inline]
+    fn load<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        #[cfg(feature = "load-timing")]
+        let start = std::time::Instant::now();
+        let value = f();
+        #[cfg(feature = "load-timing")]
+        {
+            self.stats.load_count += 1;
+            self.stats.total_load_time += start.elapsed();
+        }
         value
     }
 
-    fn meta_add(&mut self, node: Node<K, V>) {
-        self.evict();
-        let token = self.ring.insert_after(self.hand_hot);
-        self.slab[token] = Some(node);
-        self.map.insert(
-            unsafe { self.slab[token].as_ref().unsafe_unwrap().key.clone() },
-            token,
-        );
-        if self.hand_cold == self.hand_hot {
-            self.hand_cold = self.ring.prev_for_token(self.hand_cold);
+    /// Inserts a key/value pair, unless `key` is resident with a value equal
+    /// to `value`. Returns whether the cache was changed.
+    ///
+    /// Writing an equal value back leaves the entry untouched: the reference
+    /// bit is not set and the entry keeps its classification.
+    pub fn insert_if_changed(&mut self, key: K, value: V) -> bool
+    where
+        V: PartialEq,
+    {
+        if let Some(token) = self.token_for(&key) {
+            let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+            match node.value.as_mut() {
+                Some(existing) if *existing == value => return false,
+                Some(existing) => {
+                    *existing = value;
+                    node.reference(self.options.frequency_limit);
+                    self.stats.inserts += 1;
+                    return true;
+                }
+                None => {}
+            }
         }
+        // The key is not resident, so this only stores nothing when the
+        // cache is disabled
+        self.insert(key, value)
     }
 
-    fn evict(&mut self) {
-        while self.count_hot + self.count_cold >= self.capacity {
-            self.run_hand_cold();
+    /// Inserts a key/value pair like `insert`, and returns the key of the
+    /// resident entry whose value was discarded to make room for it, if any.
+    ///
+    /// With a low watermark, a single insertion can evict several entries;
+    /// only the first one is reported.
+    pub fn insert_and_report_eviction(&mut self, key: K, value: V) -> Option<K> {
+        self.evicted_keys = Some(Vec::new());
+        self.insert(key, value);
+        self.evicted_keys
+            .take()
+            .and_then(|keys| keys.into_iter().next())
+    }
+
+    /// Returns a clone of the value for `key`, inserting the value computed by
+    /// `f` on a miss.
+    ///
+    /// With values such as `Arc<T>`, this keeps lock hold times short when the
+    /// cache is shared behind a `Mutex`: the caller gets an owned value and can
+    /// release the lock right away.
+    pub fn get_cloned_or_insert_with<F>(&mut self, key: K, f: F) -> V
+    where
+        V: Clone,
+        F: FnOnce() -> V,
+    {
+        if let Some(value) = self.get(&key) {
+            return value.clone();
+        }
+        let value = self.load(f);
+        self.insert(key, value.clone());
+        value
+    }
+
+    /// Returns the value for `key`, inserting the value returned by `f` on a
+    /// miss.
+    ///
+    /// The eviction that makes room for a new entry runs before the entry is
+    /// linked, so the entry cannot be evicted by its own insertion, and the
+    /// returned reference always points at its value. Panics if the cache is
+    /// disabled.
+    pub fn get_or_insert_with<F>(&mut self, key: K, f: F) -> &mut V
+    where
+        F: FnOnce() -> V,
+    {
+        self.entry(key).or_insert_with(f)
+    }
+
+    /// Same as `insert`, returning a mutable reference to the value and
+    /// setting the reference bit of the entry, as an access would.
+    ///
+    /// Panics if the cache is disabled.
+    pub fn insert_and_get_mut(&mut self, key: K, value: V) -> &mut V {
+        assert!(!self.is_disabled(), "The cache is disabled");
+        self.insert(key.clone(), value);
+        let token = unsafe { self.token_for(&key).unsafe_unwrap() };
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        node.reference(self.options.frequency_limit);
+        unsafe { node.value.as_mut().unsafe_unwrap() }
+    }
+
+    /// Returns the value for `key`, inserting the value returned by `f` on a
+    /// miss, unless `f` returns `None`.
+    ///
+    /// When `f` declines, nothing is inserted and the clock hands do not
+    /// move; a ghost entry for `key` stays a ghost.
+    pub fn get_or_maybe_insert_with<F>(&mut self, key: K, f: F) -> Option<&V>
+    where
+        F: FnOnce() -> Option<V>,
+    {
+        let token = match self.access(&key) {
+            Some(token) => {
+                let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+                node.reference(self.options.frequency_limit);
+                token
+            }
+            None => {
+                let value = self.load(f)?;
+                if !self.insert(key.clone(), value) {
+                    return None;
+                }
+                unsafe { self.token_for(&key).unsafe_unwrap() }
+            }
+        };
+        unsafe { self.slab[token].as_ref().unsafe_unwrap().value.as_ref() }
+    }
+
+    /// Returns the state of the entry for `key` after this access, with a
+    /// mutable reference to its value, inserting `V::default()` on a miss.
+    ///
+    /// A freshly inserted entry comes back as `Cold`, or as `Hot` if `key`
+    /// was a ghost entry, unless the cache was built with
+    /// `ghost_hit_promotes_to(EntryState::Cold)`. Panics if the cache is
+    /// disabled.
+    pub fn entry_state_or_default(&mut self, key: K) -> (EntryState, &mut V)
+    where
+        V: Default,
+    {
+        let token = match self.access(&key) {
+            Some(token) => {
+                let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+                node.reference(self.options.frequency_limit);
+                token
+            }
+            None => {
+                assert!(!self.is_disabled(), "The cache is disabled");
+                self.insert(key.clone(), V::default());
+                unsafe { self.token_for(&key).unsafe_unwrap() }
+            }
+        };
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        (node.node_type.state(), unsafe {
+            node.value.as_mut().unsafe_unwrap()
+        })
+    }
+
+    /// Looks up `key` for in-place manipulation, counting it as an access.
+    pub fn entry(&mut self, key: K) -> CacheEntry<'_, K, V, S> {
+        let token = self.access(&key);
+        if let Some(token) = token {
+            let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+            node.reference(self.options.frequency_limit);
+        }
+        CacheEntry {
+            cache: self,
+            key,
+            token,
+        }
+    }
+
+    /// Simulates an access to every key of `keys`, in order: a lookup, and on
+    /// a miss, an insertion of the value returned by `loader`.
+    ///
+    /// This is meant to evaluate the cache on recorded access traces.
+    pub fn replay_trace<I>(&mut self, keys: I, mut loader: impl FnMut(&K) -> V) -> ReplayStats
+    where
+        I: IntoIterator<Item = K>,
+    {
+        let evictions = self.stats.evictions;
+        let mut replay = ReplayStats::default();
+        for key in keys {
+            if self.get(&key).is_some() {
+                replay.hits += 1;
+            } else {
+                replay.misses += 1;
+                let value = loader(&key);
+                self.insert(key, value);
+            }
+        }
+        replay.evictions = self.stats.evictions - evictions;
+        replay
+    }
+
+    /// Same as `insert`, converting the key and the value first.
+    pub fn insert_into(&mut self, key: impl Into<K>, value: impl Into<V>) -> bool {
+        self.insert(key.into(), value.into())
+    }
+
+    /// Replaces the value of a resident entry with `new` if `cond` accepts
+    /// the current value.
+    ///
+    /// On success the previous value is returned and the reference bit is
+    /// set, like an `insert` would. If the key is not resident or `cond`
+    /// rejects the current value, `new` is given back in `Err`.
+    pub fn replace_if<Q, F>(&mut self, key: &Q, new: V, cond: F) -> Result<Option<V>, V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+        F: FnOnce(&V) -> bool,
+    {
+        let token = match self.token_for(key) {
+            None => return Err(new),
+            Some(token) => token,
+        };
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        match node.value.as_ref() {
+            Some(value) if cond(value) => {
+                node.reference(self.options.frequency_limit);
+                Ok(node.value.replace(new))
+            }
+            _ => Err(new),
+        }
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let token = self.token_for(key)?;
+        self.remove_token(token)
+    }
+
+    /// Starts a batch of updates, during which evictions are deferred.
+    ///
+    /// The cache can temporarily hold more entries than its capacity while
+    /// the returned guard is alive, so entries inserted in the batch cannot
+    /// push each other out. When the guard is dropped, the clock hands run
+    /// once until the resident entries fit again.
+    pub fn batch(&mut self) -> BatchGuard<'_, K, V, S> {
+        self.defer_evictions = true;
+        BatchGuard { cache: self }
+    }
+
+    /// Forgets the ghost entry for `key`, so that it no longer occupies a slot
+    /// in the test set nor gets promoted if it comes back.
+    ///
+    /// Returns `false` if `key` is not a ghost entry; resident entries are
+    /// left alone.
+    pub fn remove_ghost<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let token = match self.token_for(key) {
+            None => return false,
+            Some(token) => token,
+        };
+        let node = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+        if !node.node_type.intersects(NodeType::TEST) {
+            return false;
+        }
+        self.remove_token(token);
+        true
+    }
+
+    /// Turns the resident cold entry for `key` into a hot one, and returns
+    /// whether it did.
+    ///
+    /// The entry is also marked as referenced. The cold capacity is lowered if
+    /// needed to make room in the hot set, but at least one resident slot
+    /// always stays cold: if all the others are already hot, nothing happens.
+    /// With `EvictionPolicy::Clock`, whose hand only evicts cold entries,
+    /// entries are never promoted.
+    pub fn promote<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if self.options.eviction_policy == EvictionPolicy::Clock {
+            return false;
+        }
+        let token = match self.token_for(key) {
+            None => return false,
+            Some(token) => token,
+        };
+        let clock_capacity = self.clock_capacity();
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        if !node.node_type.is_unpinned(NodeType::COLD) || self.count_hot + 1 >= clock_capacity {
+            return false;
+        }
+        node.node_type = NodeType::HOT;
+        node.reference(self.options.frequency_limit);
+        dec!(self.count_cold);
+        inc!(self.count_hot);
+        if self.count_hot > clock_capacity.saturating_sub(self.cold_capacity) {
+            self.set_cold_capacity(clock_capacity - self.count_hot);
+        }
+        true
+    }
+
+    /// Pins the resident entry for `key`, so that the clock hands skip it and
+    /// it is never evicted, and returns whether it did.
+    ///
+    /// Pinned entries still use resident slots, leaving fewer for the others.
+    /// If every slot was pinned, no eviction could make room for a new entry,
+    /// so pinning is refused once `capacity - 1` entries are pinned.
+    pub fn pin<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let token = match self.token_for(key) {
+            None => return false,
+            Some(token) => token,
+        };
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        if node.value.is_none()
+            || node.node_type.intersects(NodeType::PINNED)
+            || self.count_pinned + self.reserved.len() + 1 >= self.capacity
+        {
+            return false;
+        }
+        if node.node_type.intersects(NodeType::HOT) {
+            dec!(self.count_hot);
+        } else {
+            dec!(self.count_cold);
+        }
+        node.node_type.insert(NodeType::PINNED);
+        inc!(self.count_pinned);
+        true
+    }
+
+    /// Makes a pinned entry evictable again, and returns whether it was
+    /// pinned.
+    pub fn unpin<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let token = match self.token_for(key) {
+            None => return false,
+            Some(token) => token,
+        };
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        if !node.node_type.intersects(NodeType::PINNED) {
+            return false;
+        }
+        node.node_type.remove(NodeType::PINNED);
+        if node.node_type.intersects(NodeType::HOT) {
+            inc!(self.count_hot);
+        } else {
+            inc!(self.count_cold);
+        }
+        dec!(self.count_pinned);
+        true
+    }
+
+    #[inline]
+    pub fn pinned_len(&self) -> usize {
+        self.count_pinned
+    }
+
+    /// Number of resident entries that can still be evicted, that is all of
+    /// them but the pinned ones.
+    #[inline]
+    pub fn evictable_len(&self) -> usize {
+        self.count_hot + self.count_cold
+    }
+
+    // Resident slots available to the entries the hands can evict
+    #[inline]
+    fn clock_capacity(&self) -> usize {
+        self.capacity - self.count_pinned - self.reserved.len()
+    }
+
+    // Fewest resident slots that must be left to the hands. Reserved slots
+    // are not linked in the ring, which could then shrink to a single hot
+    // node that all the hands keep handing over to each other
+    #[inline]
+    fn min_clock_capacity(&self) -> usize {
+        if self.reserved.is_empty() {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Reserves a resident slot for `key` before its value is ready, so that
+    /// a loader can claim the key while it fetches the value.
+    ///
+    /// If `key` is a ghost, the ghost hit is accounted for right away, and
+    /// `fulfill` later inserts the value as a ghost hit would. Until then the
+    /// key keeps missing, and reserving it again fails. The key is handed back
+    /// if it is resident or already reserved, or if pinned entries and
+    /// reservations would leave fewer than two slots to evictable entries.
+    pub fn reserve_slot(&mut self, key: K) -> Result<SlotReservation<K>, K> {
+        if self.is_disabled()
+            || self.count_pinned + self.reserved.len() + 3 > self.capacity
+            || self.reserved.contains(&key)
+        {
+            return Err(key);
+        }
+        let ghost_hit = match self.token_for(&key) {
+            None => false,
+            Some(token) => {
+                let node = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+                if node.value.is_some() {
+                    return Err(key);
+                }
+                self.grow_cold_capacity();
+                self.remove_token(token);
+                true
+            }
+        };
+        self.reserved.push(key.clone());
+        if !self.defer_evictions {
+            self.evict_excess();
+        }
+        Ok(SlotReservation { key, ghost_hit })
+    }
+
+    /// Inserts `value` in the slot held by `reservation`, and returns whether
+    /// the key was not resident yet.
+    ///
+    /// The key may have been inserted in the meantime, in which case its
+    /// value is replaced, as with `insert`.
+    pub fn fulfill(&mut self, reservation: SlotReservation<K>, value: V) -> bool {
+        let SlotReservation { key, ghost_hit } = reservation;
+        if !self.release_reserved(&key) || !ghost_hit || self.token_for(&key).is_some() {
+            return self.insert(key, value);
+        }
+        self.stats.inserts += 1;
+        self.link_ghost_hit(key, value);
+        true
+    }
+
+    /// Gives up the slot held by `reservation` without inserting anything.
+    pub fn cancel_reservation(&mut self, reservation: SlotReservation<K>) {
+        self.release_reserved(&reservation.key);
+    }
+
+    // Returns whether `key` was reserved here; nothing stops a reservation
+    // from being passed to another cache
+    fn release_reserved(&mut self, key: &K) -> bool {
+        match self.reserved.iter().position(|reserved| reserved == key) {
+            Some(index) => {
+                self.reserved.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Keeps only the resident entries for which `f` returns `true`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.retain_with_state(|key, value, _| f(key, value))
+    }
+
+    /// Same as `retain`, also telling `f` whether each entry is hot or cold.
+    ///
+    /// Entries are only removed after `f` has returned, so if `f` panics the
+    /// cache keeps the entries it had not yet visited, with consistent counts.
+    pub fn retain_with_state<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V, EntryState) -> bool,
+    {
+        for token in 0..self.slab.len() {
+            let discard = match self.slab[token].as_mut() {
+                Some(Node {
+                    key,
+                    value: Some(value),
+                    node_type,
+                    ..
+                }) => !f(key, value, node_type.state()),
+                _ => false,
+            };
+            if discard {
+                self.remove_token(token);
+            }
+        }
+    }
+
+    /// Removes every entry, ghosts included, passing each resident key/value
+    /// pair to `f` before it is dropped.
+    ///
+    /// This lets cleanup code run for entries dropped in bulk, just as it does
+    /// for entries that come back from `insert_and_report_eviction` or
+    /// `extract_if`. The capacity and the statistics are left unchanged.
+    pub fn clear_with_callback<F>(&mut self, mut f: F)
+    where
+        F: FnMut(K, V),
+    {
+        if self.is_disabled() {
+            return;
+        }
+        // The bookkeeping is reset before `f` runs, so that a panicking
+        // callback leaves an empty but consistent cache behind
+        let storage = self
+            .options
+            .slab_growth
+            .initial_storage(self.capacity + self.test_capacity);
+        let slab = std::mem::replace(&mut self.slab, Vec::with_capacity(storage));
+        self.map.clear();
+        self.ring = TokenRing::with_capacity(storage);
+        self.hand_hot = 0;
+        self.hand_cold = 0;
+        self.hand_test = 0;
+        self.count_hot = 0;
+        self.count_cold = 0;
+        self.count_test = 0;
+        self.count_pinned = 0;
+        self.ghost_key_bytes = 0;
+        if let Some(bloom) = self.bloom.as_mut() {
+            bloom.clear();
+        }
+        for node in slab.into_iter().flatten() {
+            if let Some(value) = node.value {
+                f(node.key, value);
+            }
+        }
+    }
+
+    /// Drops every resident value, pinned ones included, but keeps the keys
+    /// as ghosts, so that the recency history survives a flush of the
+    /// contents.
+    ///
+    /// Keys inserted again afterwards are ghost hits, as long as the test
+    /// hand has not forgotten them: beyond the test capacity, the oldest
+    /// ghosts are dropped as usual.
+    /// With `EvictionPolicy::Clock`, which keeps no ghosts, the entries are
+    /// removed outright.
+    pub fn clear_resident(&mut self) {
+        if self.options.eviction_policy == EvictionPolicy::Clock {
+            // Plain CLOCK keeps no ghosts; its hand could not evict the hot
+            // entries that ghost hits would bring back
+            for token in 0..self.slab.len() {
+                if matches!(&self.slab[token], Some(node) if node.value.is_some()) {
+                    self.remove_token(token);
+                }
+            }
+            return;
+        }
+        for node in self.slab.iter_mut().flatten() {
+            if node.value.take().is_none() {
+                continue;
+            }
+            node.node_type.remove(NodeType::MASK | NodeType::PINNED);
+            node.node_type.insert(NodeType::TEST);
+            node.frequency = 0;
+            if let Some((_, byte_size)) = self.options.max_ghost_key_memory {
+                self.ghost_key_bytes += byte_size(&node.key);
+            }
+            inc!(self.count_test);
+        }
+        self.count_hot = 0;
+        self.count_cold = 0;
+        self.count_pinned = 0;
+        self.drop_excess_ghosts();
+    }
+
+    /// Removes the resident entries for which `pred` returns `true`, and
+    /// returns them.
+    pub fn extract_if<F>(&mut self, mut pred: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        let mut extracted = Vec::new();
+        for token in 0..self.slab.len() {
+            let matched = match self.slab[token].as_mut() {
+                Some(Node {
+                    key,
+                    value: Some(value),
+                    ..
+                }) => pred(key, value),
+                _ => false,
+            };
+            if matched {
+                let value = unsafe { self.remove_token(token).unsafe_unwrap() };
+                let node = unsafe { self.slab[token].take().unsafe_unwrap() };
+                extracted.push((node.key, value));
+            }
+        }
+        extracted
+    }
+
+    /// Returns a cache with the same entries, in the same states and clock
+    /// positions, where every resident value is replaced by `f(value)`.
+    ///
+    /// Ghost entries stay ghosts, and the statistics are carried over.
+    pub fn map_values<W, F>(&self, mut f: F) -> ClockProCache<K, W, S>
+    where
+        F: FnMut(&V) -> W,
+        S: Clone,
+    {
+        let slab = self
+            .slab
+            .iter()
+            .map(|node| {
+                node.as_ref().map(|node| Node {
+                    key: node.key.clone(),
+                    value: node.value.as_ref().map(&mut f),
+                    node_type: node.node_type.clone(),
+                    frequency: node.frequency,
+                    #[cfg(feature = "access-count")]
+                    accesses: node.accesses,
+                    generation: node.generation,
+                    phantom_k: PhantomData,
+                })
+            })
+            .collect();
+        ClockProCache {
+            capacity: self.capacity,
+            test_capacity: self.test_capacity,
+            cold_capacity: self.cold_capacity,
+            map: self.map.clone(),
+            ring: self.ring.clone(),
+            slab,
+            hand_hot: self.hand_hot,
+            hand_cold: self.hand_cold,
+            hand_test: self.hand_test,
+            count_hot: self.count_hot,
+            count_cold: self.count_cold,
+            count_test: self.count_test,
+            count_pinned: self.count_pinned,
+            reserved: self.reserved.clone(),
+            inserted: self.inserted,
+            next_generation: self.next_generation,
+            aging_cursor: self.aging_cursor,
+            evicted: self.evicted,
+            stats: self.stats,
+            ghost_key_bytes: self.ghost_key_bytes,
+            hands_seed: self.hands_seed,
+            defer_evictions: false,
+            evicted_keys: None,
+            ghost_hit_window: (0, 0),
+            bloom: self.bloom.clone(),
+            capacity_listener: None,
+            options: self.options.clone(),
+            #[cfg(feature = "cold-capacity-history")]
+            cold_capacity_history: self.cold_capacity_history.clone(),
+            #[cfg(feature = "access-log")]
+            access_log: self.access_log.clone(),
+            phantom_k: PhantomData,
+        }
+    }
+
+    /// Moves the resident entries whose key matches `pred` into a new cache
+    /// with the same configuration, where they start out cold.
+    pub fn split_off<F>(&mut self, mut pred: F) -> ClockProCache<K, V, S>
+    where
+        F: FnMut(&K) -> bool,
+        S: Clone,
+    {
+        let mut other = ClockProCache::from_options(
+            self.capacity,
+            self.test_capacity,
+            self.options.clone(),
+            self.map.hasher().clone(),
+        );
+        for (key, value) in self.extract_if(|key, _| pred(key)) {
+            other.insert(key, value);
+        }
+        other
+    }
+
+    /// Releases the node storage that is not used by tracked entries.
+    ///
+    /// The ring is renumbered so that the remaining nodes are stored
+    /// contiguously, then the slab, the ring and the map are shrunk to fit.
+    /// The storage grows back on demand as new entries are inserted.
+    pub fn prune_empty_slots(&mut self) {
+        self.shrink_to(0);
+    }
+
+    /// Same as `prune_empty_slots`, but keeps room for at least
+    /// `min_capacity` tracked entries, like `Vec::shrink_to`.
+    ///
+    /// Only the storage is affected: the capacity and the test capacity of
+    /// the cache, and its entries, are left unchanged.
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        self.compact(min_capacity);
+        self.slab.shrink_to(min_capacity);
+        self.map.shrink_to(min_capacity);
+    }
+
+    // Renumbers the nodes along the ring, so that tokens go from 0 to the
+    // number of tracked entries, in storage with room for `min_capacity` nodes
+    fn compact(&mut self, min_capacity: usize) {
+        let len = self.ring.len();
+        let mut ring = TokenRing::with_capacity(len.max(min_capacity).max(1));
+        let mut slab = Vec::with_capacity(len.max(min_capacity));
+        let mut remap = vec![0; self.slab.len()];
+        let mut token = self.hand_hot;
+        for _ in 0..len {
+            let new_token = ring.insert_after(0);
+            remap[token] = new_token;
+            slab.push(self.slab[token].take());
+            token = self.ring.next_for_token(token);
+        }
+        for token in self.map.values_mut() {
+            *token = remap[*token];
+        }
+        if len > 0 {
+            self.hand_hot = remap[self.hand_hot];
+            self.hand_cold = remap[self.hand_cold];
+            self.hand_test = remap[self.hand_test];
+        } else {
+            self.hand_hot = 0;
+            self.hand_cold = 0;
+            self.hand_test = 0;
+        }
+        self.ring = ring;
+        self.slab = slab;
+    }
+
+    fn remove_token(&mut self, token: Token) -> Option<V> {
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        let value = node.value.take();
+
+        if node.node_type.intersects(NodeType::PINNED) {
+            node.node_type.remove(NodeType::PINNED);
+            dec!(self.count_pinned);
+        } else if node.node_type.intersects(NodeType::HOT) {
+            dec!(self.count_hot);
+        } else if node.node_type.intersects(NodeType::COLD) {
+            dec!(self.count_cold);
+        } else if node.node_type.intersects(NodeType::TEST) {
+            dec!(self.count_test);
+        }
+
+        self.meta_del(token);
+        value
+    }
+
+    /// Inserts the pairs of `iter` as long as there is room for them without
+    /// evicting anything, and returns the rest of the iterator.
+    ///
+    /// This keeps a bulk load larger than the cache from evicting the entries
+    /// it has just inserted.
+    pub fn extend_bounded<I>(&mut self, iter: I) -> I::IntoIter
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut iter = iter.into_iter();
+        while self.count_hot + self.count_cold < self.clock_capacity() {
+            match iter.next() {
+                Some((key, value)) => self.insert(key, value),
+                None => break,
+            };
+        }
+        iter
+    }
+
+    /// Consumes the cache, yielding its resident key/value pairs in no
+    /// particular order.
+    ///
+    /// Meant for migrating data to another cache. Ghost entries and the clock
+    /// state are dropped; see `export_keys` to keep a priority order.
+    pub fn export_pairs(self) -> impl Iterator<Item = (K, V)> {
+        self.slab
+            .into_iter()
+            .flatten()
+            .filter_map(|node| Some((node.key, node.value?)))
+    }
+
+    /// Consumes the cache, returning its resident key/value pairs as a plain
+    /// `HashMap`. Ghost entries are dropped.
+    pub fn into_hashmap(self) -> HashMap<K, V> {
+        self.export_pairs().collect()
+    }
+
+    /// Inserts all the resident entries of `other` into this cache.
+    ///
+    /// The entries go through `insert`, so this cache's capacity is respected
+    /// and the merged entries start out cold. Ghost entries of `other` are
+    /// ignored.
+    pub fn merge<S2>(&mut self, other: ClockProCache<K, V, S2>) {
+        for node in other.slab.into_iter().flatten() {
+            let value = match node.value {
+                None => continue,
+                Some(value) => value,
+            };
+            if !self.options.merge_overwrites && self.contains_key(&node.key) {
+                continue;
+            }
+            self.insert(node.key, value);
+        }
+    }
+
+    /// Walks the clock from the cold hand, yielding every tracked key with
+    /// its value, or `None` for ghost entries.
+    pub fn iter_clock_order(&self) -> ClockIter<'_, K, V> {
+        ClockIter {
+            ring: &self.ring,
+            slab: &self.slab,
+            token: self.hand_cold,
+            remaining: self.ring.len(),
+        }
+    }
+
+    /// Returns the keys of the resident entries, hot entries first, each
+    /// group in clock order from the cold hand.
+    ///
+    /// The keys can be passed to `import_keys` in another cache, or used to
+    /// prefetch values from the source in priority order.
+    pub fn export_keys(&self) -> Vec<K> {
+        let (mut hot, cold): (Vec<_>, Vec<_>) = self
+            .iter_clock_order()
+            .filter(|(_, value)| value.is_some())
+            .map(|(key, _)| {
+                let token = self.map[key];
+                let node = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+                (key.clone(), node.node_type.state() == EntryState::Hot)
+            })
+            .partition(|&(_, hot)| hot);
+        hot.extend(cold);
+        hot.into_iter().map(|(key, _)| key).collect()
+    }
+
+    /// Tracks `keys` as ghost entries, so that inserting them later brings
+    /// them straight into the hot set.
+    ///
+    /// Keys that are already tracked are skipped, and the import stops once
+    /// the ghost entries reach the test capacity. Returns the number of keys
+    /// that were imported. With `EvictionPolicy::Clock`, which keeps no
+    /// ghosts, nothing is imported.
+    pub fn import_keys<I>(&mut self, keys: I) -> usize
+    where
+        I: IntoIterator<Item = K>,
+    {
+        // The CLOCK hand could not evict the hot entries that ghost hits on
+        // the imported keys would bring in
+        if self.options.eviction_policy == EvictionPolicy::Clock {
+            return 0;
+        }
+        let mut imported = 0;
+        for key in keys {
+            if self.count_test >= self.test_capacity || self.ghost_keys_over_budget() {
+                break;
+            }
+            if self.map.contains_key(&key) {
+                continue;
+            }
+            if let Some((_, byte_size)) = self.options.max_ghost_key_memory {
+                self.ghost_key_bytes += byte_size(&key);
+            }
+            self.options.slab_growth.reserve_ring(&mut self.ring);
+            let token = self.ring.insert_after(self.hand_hot);
+            self.map.insert(key.clone(), token);
+            self.bloom_insert(&key);
+            let node = Node {
+                key,
+                value: None,
+                node_type: NodeType::TEST,
+                frequency: 0,
+                #[cfg(feature = "access-count")]
+                accesses: 0,
+                generation: 0,
+                phantom_k: PhantomData,
+            };
+            self.meta_link(token, node);
+            inc!(self.count_test);
+            imported += 1;
+        }
+        imported
+    }
+
+    /// Iterates over the tracked entries in `state`, in no particular order,
+    /// with their values, which are `None` for ghost entries.
+    ///
+    /// Pinned entries are yielded under their state, although `frequent_len`
+    /// and `recent_len` do not count them.
+    pub fn iter_state(&self, state: EntryState) -> impl Iterator<Item = (&K, Option<&V>)> {
+        self.slab
+            .iter()
+            .flatten()
+            .filter(move |node| {
+                !node.node_type.intersects(NodeType::EMPTY) && node.node_type.state() == state
+            })
+            .map(|node| (&node.key, node.value.as_ref()))
+    }
+
+    /// Returns some hot entry whose reference bit is set, which is likely
+    /// part of the working set, without touching it.
+    ///
+    /// This is a best-effort diagnostic helper: CLOCK-Pro does not track the
+    /// most recently or most frequently used entry, and which of the matching
+    /// entries is returned is unspecified.
+    pub fn peek_hot_sample(&self) -> Option<(&K, &V)> {
+        self.slab.iter().flatten().find_map(|node| {
+            if !node.node_type.contains(NodeType::HOT | NodeType::REFERENCE) {
+                return None;
+            }
+            Some((&node.key, node.value.as_ref()?))
+        })
+    }
+
+    /// Yields at most `max` resident entries, in no particular order, without
+    /// touching the eviction state; meant for inspecting a large cache
+    /// without dumping all of it.
+    pub fn iter_limited(&self, max: usize) -> impl Iterator<Item = (&K, &V)> {
+        self.resident().take(max)
+    }
+
+    /// Folds `f` over the resident values, in no particular order, without
+    /// touching the eviction state; handy to total sizes or counters.
+    pub fn fold_values<B, F>(&self, init: B, mut f: F) -> B
+    where
+        F: FnMut(B, &V) -> B,
+    {
+        self.resident().fold(init, |acc, (_, value)| f(acc, value))
+    }
+
+    fn resident(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.slab
+            .iter()
+            .flatten()
+            .filter_map(|node| node.value.as_ref().map(|value| (&node.key, value)))
+    }
+
+    fn resident_value<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let token = self.token_for(key)?;
+        unsafe { self.slab[token].as_ref().unsafe_unwrap().value.as_ref() }
+    }
+
+    // Looks up a resident entry, and records the lookup in the statistics
+    #[inline]
+    fn access<Q>(&mut self, key: &Q) -> Option<Token>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.age_frequencies();
+        let token = match self.token_for(key) {
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+            Some(token) => token,
+        };
+        let node = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+        if node.value.is_none() {
+            self.stats.misses += 1;
+            #[cfg(feature = "access-log")]
+            self.log_access(node.key.clone(), false);
+            return None;
+        }
+        self.record_hit(token);
+        Some(token)
+    }
+
+    // Halves the counters of the next slice of the slab, sized so that every
+    // node is aged once per `aging_interval` lookups
+    #[inline]
+    fn age_frequencies(&mut self) {
+        let interval = match self.options.aging_interval {
+            None => return,
+            Some(interval) => interval,
+        };
+        let len = self.slab.len();
+        for _ in 0..len.div_ceil(interval) {
+            if self.aging_cursor >= len {
+                self.aging_cursor = 0;
+            }
+            if let Some(node) = self.slab[self.aging_cursor].as_mut() {
+                node.frequency /= 2;
+            }
+            self.aging_cursor += 1;
+        }
+    }
+
+    #[inline]
+    fn record_hit(&mut self, token: Token) {
+        let node = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        self.stats.hits += 1;
+        if node.node_type.intersects(NodeType::HOT) {
+            self.stats.hot_hits += 1;
+        } else {
+            self.stats.cold_hits += 1;
+        }
+        #[cfg(feature = "access-count")]
+        {
+            node.accesses += 1;
+        }
+        #[cfg(feature = "access-log")]
+        {
+            let key = node.key.clone();
+            self.log_access(key, true);
+        }
+    }
+
+    #[cfg(feature = "access-log")]
+    fn log_access(&mut self, key: K, hit: bool) {
+        // Trim by halves to keep the cost of recording amortized O(1)
+        if self.access_log.len() >= 2 * ACCESS_LOG_LEN {
+            self.access_log.drain(..ACCESS_LOG_LEN);
+        }
+        self.access_log.push((key, hit));
+    }
+
+    #[inline]
+    fn token_for<Q>(&self, key: &Q) -> Option<Token>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if let Some(bloom) = &self.bloom {
+            if !bloom.may_contain(self.map.hasher().hash_one(key)) {
+                return None;
+            }
+        }
+        let token = *self.map.get(key)?;
+        debug_assert!(
+            matches!(
+                self.slab.get(token),
+                Some(Some(node)) if node.key.borrow() == key
+                    && !node.node_type.intersects(NodeType::EMPTY)
+            ),
+            "The map and the slab are out of sync"
+        );
+        Some(token)
+    }
+
+    // Every change goes through here, so that the bounds the hands rely on
+    // are checked in one place: the cold and the hot set both get a slot
+    fn set_cold_capacity(&mut self, cold_capacity: usize) {
+        debug_assert!(
+            (1..self.capacity).contains(&cold_capacity),
+            "The cold capacity must stay between 1 and the capacity minus 1"
+        );
+        self.cold_capacity = cold_capacity;
+        #[cfg(feature = "cold-capacity-history")]
+        {
+            // Trim by halves to keep the cost of recording amortized O(1)
+            if self.cold_capacity_history.len() >= 2 * COLD_CAPACITY_HISTORY_LEN {
+                self.cold_capacity_history
+                    .drain(..COLD_CAPACITY_HISTORY_LEN);
+            }
+            self.cold_capacity_history.push(cold_capacity);
+        }
+    }
+
+    fn meta_add(&mut self, node: Node<K, V>) {
+        self.evict();
+        self.options.slab_growth.reserve_ring(&mut self.ring);
+        let token = self.ring.insert_after(self.hand_hot);
+        self.map.insert(node.key.clone(), token);
+        self.bloom_insert(&node.key);
+        self.meta_link(token, node);
+    }
+
+    fn bloom_insert(&mut self, key: &K) {
+        let bloom = match &mut self.bloom {
+            None => return,
+            Some(bloom) => bloom,
+        };
+        bloom.insert(self.map.hasher().hash_one(key));
+        if bloom.is_saturated() {
+            bloom.clear();
+            for key in self.map.keys() {
+                bloom.insert(self.map.hasher().hash_one(key));
+            }
+        }
+    }
+
+    // The slab is only a lookup table for ring tokens, so it follows the ring
+    // instead of the logical capacity, growing geometrically like any `Vec`
+    // unless the growth strategy says otherwise
+    fn meta_link(&mut self, token: Token, mut node: Node<K, V>) {
+        if token >= self.slab.len() {
+            if let SlabGrowth::Increment(step) = self.options.slab_growth {
+                if token >= self.slab.capacity() {
+                    self.slab.reserve_exact(token + step - self.slab.len());
+                }
+            }
+            self.slab.resize_with(token + 1, || None);
+        }
+        node.generation = self.next_generation;
+        self.next_generation += 1;
+        self.slab[token] = Some(node);
+        if self.ring.len() == 1 {
+            // The hands may still point at the node that emptied the ring
+            self.hand_hot = token;
+            self.hand_cold = token;
+            self.hand_test = token;
+        }
+        if self.hand_cold == self.hand_hot {
+            self.hand_cold = self.ring.prev_for_token(self.hand_cold);
+        }
+    }
+
+    fn evict(&mut self) {
+        if self.defer_evictions || self.count_hot + self.count_cold < self.clock_capacity() {
+            return;
+        }
+        if let Some(seed) = self.hands_seed.take() {
+            self.randomize_hands(seed);
+        }
+        while self.count_hot + self.count_cold >= self.eviction_target() {
+            self.run_eviction_hand();
+        }
+    }
+
+    // Number of resident entries below which an eviction stops. The hands
+    // cannot go under the hot target, so neither can a low watermark; the
+    // target is recomputed as the cold capacity moves during the eviction
+    #[inline]
+    fn eviction_target(&self) -> usize {
+        match self.options.eviction_low_watermark {
+            None => self.clock_capacity(),
+            Some(watermark) => watermark
+                .max(self.hot_target() + 1)
+                .min(self.clock_capacity()),
+        }
+    }
+
+    // Runs the hands until the resident entries fit in the capacity
+    fn evict_excess(&mut self) {
+        while self.count_hot + self.count_cold > self.clock_capacity() {
+            self.run_eviction_hand();
+        }
+    }
+
+    #[inline]
+    fn run_eviction_hand(&mut self) {
+        match self.options.eviction_policy {
+            EvictionPolicy::ClockPro => self.run_hand_cold(),
+            EvictionPolicy::Clock => self.run_clock_hand(),
+        }
+    }
+
+    // With plain CLOCK, every resident entry is cold and the cold hand is the
+    // only one moving
+    fn run_clock_hand(&mut self) {
+        let token = self.hand_cold;
+        let mentry = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+        if mentry.node_type.is_unpinned(NodeType::COLD) && !mentry.consume_reference() {
+            dec!(self.count_cold);
+            self.stats.evictions += 1;
+            if let Some(keys) = self.evicted_keys.as_mut() {
+                keys.push(mentry.key.clone());
+            }
+            self.meta_del(token);
+            if self.ring.len() == 0 {
+                // A low watermark can evict every entry
+                return;
+            }
+        }
+        self.hand_cold = self.ring.next_for_token(self.hand_cold);
+    }
+
+    fn randomize_hands(&mut self, mut seed: u64) {
+        let len = self.ring.len() as u64;
+        for hand in [&mut self.hand_hot, &mut self.hand_cold, &mut self.hand_test] {
+            for _ in 0..splitmix64(&mut seed) % len {
+                *hand = self.ring.next_for_token(*hand);
+            }
         }
     }
 
     fn run_hand_cold(&mut self) {
+        self.prefetch_node(self.ring.next_for_token(self.hand_cold));
         let mut run_hand_test = false;
         {
-            let mentry = unsafe { self.slab[self.hand_cold].as_mut().unsafe_unwrap() };
-            if mentry.node_type.intersects(NodeType::COLD) {
-                if mentry.node_type.intersects(NodeType::REFERENCE) {
-                    mentry.node_type = NodeType::HOT;
-                    self.count_cold -= 1;
-                    self.count_hot += 1;
-                } else {
-                    mentry.node_type.remove(NodeType::MASK);
-                    mentry.node_type.insert(NodeType::TEST);
-                    mentry.value = None;
-                    self.count_cold -= 1;
-                    self.count_test += 1;
-                    run_hand_test = true
+            let mentry = unsafe { self.slab[self.hand_cold].as_mut().unsafe_unwrap() };
+            if mentry.node_type.is_unpinned(NodeType::COLD) {
+                if mentry.consume_reference() {
+                    let referenced = mentry.node_type.intersects(NodeType::REFERENCE);
+                    mentry.node_type = NodeType::HOT;
+                    mentry.node_type.set(NodeType::REFERENCE, referenced);
+                    dec!(self.count_cold);
+                    inc!(self.count_hot);
+                } else {
+                    mentry.node_type.remove(NodeType::MASK);
+                    mentry.node_type.insert(NodeType::TEST);
+                    mentry.value = None;
+                    mentry.frequency = 0;
+                    dec!(self.count_cold);
+                    inc!(self.count_test);
+                    self.stats.evictions += 1;
+                    if let Some(keys) = self.evicted_keys.as_mut() {
+                        keys.push(mentry.key.clone());
+                    }
+                    if let Some((_, byte_size)) = self.options.max_ghost_key_memory {
+                        self.ghost_key_bytes += byte_size(&mentry.key);
+                    }
+                    run_hand_test = true
+                }
+            }
+        }
+        if run_hand_test {
+            while self.count_test > self.test_capacity || self.ghost_keys_over_budget() {
+                self.run_hand_test();
+            }
+            if self.ring.len() == 0 {
+                // The test hand forgot the last node, the hand has nowhere to go
+                return;
+            }
+        }
+        self.hand_cold = self.ring.next_for_token(self.hand_cold);
+        while self.count_hot > self.hot_target() {
+            self.run_hand_hot();
+        }
+    }
+
+    // Number of hot entries above which the hot hand demotes them; the
+    // floor leaves at least one cold slot for the cold hand to evict from.
+    // It also keeps a hot slot, as the cold capacity below the capacity does
+    // when no slot is pinned or reserved: with none, the hands would chase a
+    // lone hot node around the ring once the others are evicted
+    #[inline]
+    fn hot_target(&self) -> usize {
+        let clock_capacity = self.clock_capacity();
+        let floor = self
+            .options
+            .min_hot
+            .max(1)
+            .min(clock_capacity.saturating_sub(1));
+        clock_capacity.saturating_sub(self.cold_capacity).max(floor)
+    }
+
+    // Hints the CPU to load the node the hand will look at next, while the
+    // current one is being processed
+    #[inline]
+    fn prefetch_node(&self, _token: Token) {
+        #[cfg(all(feature = "prefetch", target_arch = "x86_64"))]
+        if let Some(node) = self.slab.get(_token) {
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            unsafe { _mm_prefetch::<_MM_HINT_T0>(node as *const _ as *const i8) };
+        }
+    }
+
+    #[inline]
+    fn ghost_keys_over_budget(&self) -> bool {
+        match self.options.max_ghost_key_memory {
+            None => false,
+            Some((max_bytes, _)) => self.ghost_key_bytes > max_bytes,
+        }
+    }
+
+    fn run_hand_hot(&mut self) {
+        self.prefetch_node(self.ring.next_for_token(self.hand_hot));
+        if self.hand_hot == self.hand_test {
+            self.run_hand_test();
+        }
+        {
+            let mentry = unsafe { self.slab[self.hand_hot].as_mut().unsafe_unwrap() };
+            if mentry.node_type.is_unpinned(NodeType::HOT) && !mentry.consume_reference() {
+                mentry.node_type.remove(NodeType::MASK);
+                mentry.node_type.insert(NodeType::COLD);
+                dec!(self.count_hot);
+                inc!(self.count_cold);
+            }
+        }
+        self.hand_hot = self.ring.next_for_token(self.hand_hot);
+    }
+
+    fn run_hand_test(&mut self) {
+        if self.hand_test == self.hand_cold {
+            self.run_hand_cold();
+            if self.ring.len() == 0 {
+                // The cold hand evicted and forgot the last nodes
+                return;
+            }
+        }
+        if unsafe {
+            self.slab[self.hand_test]
+                .as_ref()
+                .unsafe_unwrap()
+                .node_type
+                .intersects(NodeType::TEST)
+        } {
+            let prev = self.ring.prev_for_token(self.hand_test);
+            let hand_test = self.hand_test;
+            self.meta_del(hand_test);
+            dec!(self.count_test);
+            self.stats.ghost_removals += 1;
+            if self.cold_capacity > 1 {
+                self.set_cold_capacity(counter_sub!(self.cold_capacity));
+            }
+            if self.ring.len() == 0 {
+                // That was the last node, there is nothing to move to
+                return;
+            }
+            self.hand_test = prev;
+        }
+        self.hand_test = self.ring.next_for_token(self.hand_test);
+    }
+
+    // Forgets the ghosts over the test capacity or the ghost key memory
+    // budget, the oldest first, from the test hand onwards. Unlike
+    // `run_hand_test`, this never moves the cold hand, so resident entries are
+    // left alone, and as the ghosts are not expiring on their own the cold
+    // capacity is not adapted either.
+    fn drop_excess_ghosts(&mut self) {
+        while self.count_test > self.test_capacity || self.ghost_keys_over_budget() {
+            let mut token = self.hand_test;
+            while !unsafe { self.slab[token].as_ref().unsafe_unwrap() }
+                .node_type
+                .intersects(NodeType::TEST)
+            {
+                token = self.ring.next_for_token(token);
+            }
+            self.hand_test = self.ring.next_for_token(token);
+            self.meta_del(token);
+            dec!(self.count_test);
+            self.stats.ghost_removals += 1;
+        }
+    }
+
+    fn meta_del(&mut self, token: Token) {
+        {
+            let mentry = unsafe { self.slab[token].as_ref().unsafe_unwrap() };
+            self.map.remove(&mentry.key);
+        }
+        self.meta_unlink(token);
+    }
+
+    // Same as `meta_del`, for nodes whose key has already left the map
+    fn meta_unlink(&mut self, token: Token) {
+        {
+            let mentry = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
+            if mentry.node_type.intersects(NodeType::TEST) {
+                if let Some((_, byte_size)) = self.options.max_ghost_key_memory {
+                    self.ghost_key_bytes -= byte_size(&mentry.key);
+                }
+            }
+            mentry.node_type.remove(NodeType::MASK);
+            mentry.node_type.insert(NodeType::EMPTY);
+            mentry.value = None;
+        }
+        if token == self.hand_hot {
+            self.hand_hot = self.ring.prev_for_token(self.hand_hot);
+        }
+        if token == self.hand_cold {
+            self.hand_cold = self.ring.prev_for_token(self.hand_cold);
+        }
+        if token == self.hand_test {
+            self.hand_test = self.ring.prev_for_token(self.hand_test);
+        }
+        self.ring.remove(token);
+        self.evicted += 1;
+    }
+}
+
+/// The test capacity used by `ClockProCache::auto`: `capacity` up to 4096
+/// entries, then `4096 + (capacity - 4096) / 8`.
+pub fn auto_test_capacity(capacity: usize) -> usize {
+    const FULL_HISTORY: usize = 4096;
+    if capacity <= FULL_HISTORY {
+        capacity
+    } else {
+        FULL_HISTORY + (capacity - FULL_HISTORY) / 8
+    }
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Compares the resident entries of two caches.
+///
+/// This is a content comparison: the clock state, the classification of the
+/// entries and the ghost entries are not taken into account.
+impl<K, V, S> PartialEq for ClockProCache<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    V: PartialEq,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .resident()
+                .all(|(key, value)| other.resident_value(key) == Some(value))
+    }
+}
+
+impl<K, V, S> Eq for ClockProCache<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    V: Eq,
+    S: BuildHasher,
+{
+}
+
+/// Formats the resident entries, so that caches can be used with `assert_eq!`.
+impl<K, V, S> fmt::Debug for ClockProCache<K, V, S>
+where
+    K: Eq + Hash + Clone + fmt::Debug,
+    V: fmt::Debug,
+    S: BuildHasher,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.resident()).finish()
+    }
+}
+
+/// A batch of updates, returned by `ClockProCache::batch`.
+pub struct BatchGuard<'a, K, V, S = RandomState>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    cache: &'a mut ClockProCache<K, V, S>,
+}
+
+impl<'a, K, V, S> BatchGuard<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.cache.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        self.cache.insert(key, value)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.cache.remove(key)
+    }
+}
+
+/// A resident or missing entry, returned by `ClockProCache::entry`.
+pub struct CacheEntry<'a, K, V, S = RandomState>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    cache: &'a mut ClockProCache<K, V, S>,
+    key: K,
+    // `None` if the entry is not resident
+    token: Option<Token>,
+}
+
+impl<'a, K, V, S> CacheEntry<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Calls `f` with the value if the entry is resident.
+    pub fn and_modify<F>(self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        self.and_modify_with_state(|value, _| f(value))
+    }
+
+    /// Calls `f` with the value and the state of the entry, hot or cold, if
+    /// the entry is resident.
+    pub fn and_modify_with_state<F>(self, f: F) -> Self
+    where
+        F: FnOnce(&mut V, EntryState),
+    {
+        if let Some(token) = self.token {
+            let node = unsafe { self.cache.slab[token].as_mut().unsafe_unwrap() };
+            let state = node.node_type.state();
+            f(unsafe { node.value.as_mut().unsafe_unwrap() }, state);
+        }
+        self
+    }
+
+    /// Returns the value, inserting `default` if the entry is not resident.
+    ///
+    /// Panics if the cache is disabled.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value, inserting the result of `f` if the entry is not
+    /// resident.
+    ///
+    /// Panics if the cache is disabled.
+    pub fn or_insert_with<F>(self, f: F) -> &'a mut V
+    where
+        F: FnOnce() -> V,
+    {
+        let cache = self.cache;
+        let token = match self.token {
+            Some(token) => token,
+            None => {
+                assert!(!cache.is_disabled(), "The cache is disabled");
+                let value = cache.load(f);
+                cache.insert(self.key.clone(), value);
+                unsafe { cache.token_for(&self.key).unsafe_unwrap() }
+            }
+        };
+        let node = unsafe { cache.slab[token].as_mut().unsafe_unwrap() };
+        unsafe { node.value.as_mut().unsafe_unwrap() }
+    }
+}
+
+/// A resident value, returned by `ClockProCache::peek_mut_guard`.
+pub struct PeekMutGuard<'a, K, V> {
+    node: &'a mut Node<K, V>,
+    frequency_limit: Option<u8>,
+    dirty: bool,
+}
+
+impl<'a, K, V> Deref for PeekMutGuard<'a, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        unsafe { self.node.value.as_ref().unsafe_unwrap() }
+    }
+}
+
+impl<'a, K, V> DerefMut for PeekMutGuard<'a, K, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.dirty = true;
+        unsafe { self.node.value.as_mut().unsafe_unwrap() }
+    }
+}
+
+impl<'a, K, V> Drop for PeekMutGuard<'a, K, V> {
+    fn drop(&mut self) {
+        if self.dirty {
+            self.node.reference(self.frequency_limit);
+        }
+    }
+}
+
+impl<'a, K, V, S> Drop for BatchGuard<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    fn drop(&mut self) {
+        self.cache.defer_evictions = false;
+        self.cache.evict_excess();
+    }
+}
+
+/// A cache that never evicts silently: inserting a new key when every slot is
+/// taken fails instead of pushing an entry out.
+pub struct StrictClockProCache<K, V, S = RandomState>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    cache: ClockProCache<K, V, S>,
+}
+
+impl<K, V, S> StrictClockProCache<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    pub fn new(cache: ClockProCache<K, V, S>) -> Self {
+        StrictClockProCache { cache }
+    }
+
+    pub fn into_inner(self) -> ClockProCache<K, V, S> {
+        self.cache
+    }
+
+    /// Whether a new key would have to evict a resident entry.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.cache.evictable_len() >= self.cache.clock_capacity()
+    }
+
+    /// Inserts or overwrites the entry for `key`, handing the pair back if
+    /// the key is not resident and the cache is full.
+    pub fn insert(&mut self, key: K, value: V) -> Result<bool, (K, V)> {
+        let resident = self
+            .cache
+            .inspect(&key)
+            .is_some_and(|info| info.value.is_some());
+        if !resident && self.is_full() {
+            return Err((key, value));
+        }
+        Ok(self.cache.insert(key, value))
+    }
+
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.cache.get(key)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.cache.get_mut(key)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.cache.remove(key)
+    }
+}
+
+impl<K, V, S> From<ClockProCache<K, V, S>> for StrictClockProCache<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    fn from(cache: ClockProCache<K, V, S>) -> Self {
+        StrictClockProCache::new(cache)
+    }
+}
+
+impl<K, V, S> Deref for StrictClockProCache<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    type Target = ClockProCache<K, V, S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cache
+    }
+}
+
+pub struct ClockIter<'a, K, V> {
+    ring: &'a TokenRing,
+    slab: &'a [Option<Node<K, V>>],
+    token: Token,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iterator for ClockIter<'a, K, V> {
+    type Item = (&'a K, Option<&'a V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = unsafe { self.slab[self.token].as_ref().unsafe_unwrap() };
+        self.token = self.ring.next_for_token(self.token);
+        self.remaining -= 1;
+        Some((&node.key, node.value.as_ref()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for ClockIter<'a, K, V> {}
+
+mod bloom {
+    const BITS_PER_ITEM: usize = 10;
+    const HASHES: u64 = 7;
+
+    /// A Bloom filter over precomputed 64-bit hashes, for about 1% false
+    /// positives at the expected number of items.
+    #[derive(Clone)]
+    pub struct BloomFilter {
+        bits: Vec<u64>,
+        expected_items: usize,
+        insertions: usize,
+    }
+
+    // Double hashing: the bit positions are `h1 + i * h2`
+    fn positions(words: usize, hash: u64) -> impl Iterator<Item = usize> {
+        let len = words as u64 * 64;
+        let (h1, h2) = (hash, hash.rotate_left(32) | 1);
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    impl BloomFilter {
+        pub fn new(expected_items: usize) -> Self {
+            let words = (expected_items.max(1) * BITS_PER_ITEM).div_ceil(64);
+            BloomFilter {
+                bits: vec![0; words],
+                expected_items: expected_items.max(1),
+                insertions: 0,
+            }
+        }
+
+        pub fn insert(&mut self, hash: u64) {
+            for position in positions(self.bits.len(), hash) {
+                self.bits[position / 64] |= 1 << (position % 64);
+            }
+            self.insertions += 1;
+        }
+
+        pub fn may_contain(&self, hash: u64) -> bool {
+            positions(self.bits.len(), hash)
+                .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+        }
+
+        /// Bits cannot be cleared, so removed keys keep matching until the
+        /// filter is rebuilt. This says when it has become too crowded.
+        pub fn is_saturated(&self) -> bool {
+            self.insertions > 2 * self.expected_items
+        }
+
+        pub fn clear(&mut self) {
+            self.bits.iter_mut().for_each(|word| *word = 0);
+            self.insertions = 0;
+        }
+    }
+}
+
+mod token_ring {
+    use slab::Slab;
+
+    pub type Token = usize;
+    const TOKEN_THUMBSTONE: Token = !0;
+
+    #[derive(Clone)]
+    pub struct Node {
+        next: Token,
+        prev: Token,
+    }
+
+    #[derive(Clone)]
+    pub struct TokenRing {
+        head: Token,
+        tail: Token,
+        slab: Slab<Node>,
+    }
+
+    impl TokenRing {
+        pub fn with_capacity(capacity: usize) -> Self {
+            if capacity < 1 {
+                panic!("A ring cannot have a capacity smaller than 1");
+            }
+            let slab = Slab::with_capacity(capacity);
+            TokenRing {
+                head: TOKEN_THUMBSTONE,
+                tail: TOKEN_THUMBSTONE,
+                slab,
+            }
+        }
+
+        pub fn reserve(&mut self, additional: usize) {
+            self.slab.reserve(additional);
+        }
+
+        pub fn reserve_exact(&mut self, additional: usize) {
+            self.slab.reserve_exact(additional);
+        }
+
+        pub fn capacity(&self) -> usize {
+            self.slab.capacity()
+        }
+
+        #[inline]
+        pub fn len(&self) -> usize {
+            self.slab.len()
+        }
+
+        #[inline]
+        pub fn next_for_token(&self, token: Token) -> Token {
+            let next = self.slab[token].next;
+            if next == TOKEN_THUMBSTONE {
+                assert!(self.head != TOKEN_THUMBSTONE);
+                self.head
+            } else {
+                next
+            }
+        }
+
+        #[inline]
+        pub fn prev_for_token(&self, token: Token) -> Token {
+            let prev = self.slab[token].prev;
+            if prev == TOKEN_THUMBSTONE {
+                assert!(self.tail != TOKEN_THUMBSTONE);
+                self.tail
+            } else {
+                prev
+            }
+        }
+
+        pub fn remove(&mut self, token: Token) {
+            let (prev, next) = (self.slab[token].prev, self.slab[token].next);
+            if prev != TOKEN_THUMBSTONE {
+                self.slab[prev].next = next;
+            } else {
+                self.head = next;
+            }
+            if next != TOKEN_THUMBSTONE {
+                self.slab[next].prev = prev;
+            } else {
+                self.tail = prev;
+            }
+            self.slab[token].prev = TOKEN_THUMBSTONE;
+            self.slab[token].next = TOKEN_THUMBSTONE;
+            self.slab.remove(token);
+        }
+
+        pub fn insert_after(&mut self, to: Token) -> Token {
+            if self.slab.is_empty() {
+                let node = Node {
+                    prev: TOKEN_THUMBSTONE,
+                    next: TOKEN_THUMBSTONE,
+                };
+                let token = self.slab.insert(node);
+                self.head = token;
+                self.tail = token;
+                return token;
+            }
+            let to_prev = self.slab[to].prev;
+            let old_second = to_prev;
+            if old_second == TOKEN_THUMBSTONE {
+                let old_second = self.tail;
+                let node = Node {
+                    prev: old_second,
+                    next: TOKEN_THUMBSTONE,
+                };
+                let token = self.slab.insert(node);
+                self.slab[old_second].next = token;
+                self.tail = token;
+                token
+            } else {
+                let node = Node {
+                    prev: old_second,
+                    next: to,
+                };
+                let token = self.slab.insert(node);
+                self.slab[old_second].next = token;
+                self.slab[to].prev = token;
+                token
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BoxedClockProCache, CacheStats, CapacityChange, ClockProCache, EntryState, EvictionPolicy,
+        GetResult, Handle, NodeType, Occupancy, RepairReport, SlabGrowth, SlotReservation,
+        StrictClockProCache,
+    };
+    use std::hash::BuildHasher;
+    use std::marker::PhantomData;
+
+    fn check_invariants<V>(cache: &ClockProCache<u64, V>) {
+        let (mut hot, mut cold, mut test, mut pinned) = (0, 0, 0, 0);
+        for (key, &token) in &cache.map {
+            let node = cache.slab[token].as_ref().unwrap();
+            assert_eq!(node.key, *key);
+            assert!(!node.node_type.intersects(NodeType::EMPTY));
+            if node.node_type.intersects(NodeType::PINNED) {
+                pinned += 1;
+            } else if node.node_type.intersects(NodeType::HOT) {
+                hot += 1;
+            } else if node.node_type.intersects(NodeType::COLD) {
+                cold += 1;
+            } else {
+                assert!(node.value.is_none());
+                test += 1;
+            }
+        }
+        assert_eq!(
+            (hot, cold, test, pinned),
+            (
+                cache.count_hot,
+                cache.count_cold,
+                cache.count_test,
+                cache.count_pinned
+            )
+        );
+        assert_eq!(cache.ring.len(), cache.map.len());
+    }
+
+    fn referenced<V>(cache: &ClockProCache<u64, V>, key: &u64) -> bool {
+        cache.slab[cache.map[key]]
+            .as_ref()
+            .unwrap()
+            .node_type
+            .intersects(NodeType::REFERENCE)
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut cache = ClockProCache::new(3).unwrap();
+        cache.insert("testkey", "testvalue");
+        assert!(cache.contains_key("testkey"));
+        cache.insert("testkey2", "testvalue2");
+        assert!(cache.contains_key("testkey2"));
+        cache.insert("testkey3", "testvalue3");
+        assert!(cache.contains_key("testkey3"));
+        cache.insert("testkey4", "testvalue4");
+        assert!(cache.contains_key("testkey4"));
+        assert!(cache.contains_key("testkey3"));
+        assert!(!cache.contains_key("testkey2"));
+        cache.insert("testkey", "testvalue");
+        assert!(cache.get_mut("testkey").is_some());
+        assert!(cache.get_mut("testkey-nx").is_none());
+    }
+
+    #[test]
+    fn test_recycle() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        for i in 0..7 {
+            assert!(cache.insert(i, i));
+        }
+        for i in 0..2 {
+            match cache.get(&i) {
+                None => {}
+                Some(x) => assert_eq!(*x, i),
+            }
+        }
+    }
+
+    #[test]
+    fn test_composite() {
+        let mut cache: ClockProCache<u64, (Vec<u8>, u64)> = ClockProCache::new(3).unwrap();
+        for i in 0..7 {
+            assert!(cache.insert(i, (vec![0u8; 12], i)));
+        }
+        for i in 0..2 {
+            match cache.get(&i) {
+                None => {}
+                Some(x) => assert_eq!(x.1, i),
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(4).unwrap();
+        for i in 0..4 {
+            assert!(cache.insert(i, i));
+        }
+
+        assert_eq!(cache.remove(&2), Some(2));
+        assert_eq!(cache.remove(&3), Some(3));
+        assert_eq!(cache.remove(&3), None);
+
+        for i in 0..4 {
+            match i {
+                2 | 3 => assert_eq!(cache.get(&i), None),
+                _ => assert_eq!(*cache.get(&i).unwrap(), i),
+            };
+        }
+
+        // Reinsert removed entries
+        for i in 2..4 {
+            assert!(cache.insert(i, i));
+        }
+
+        // Check that all entries still exist
+        for i in 0..4 {
+            assert_eq!(*cache.get(&i).unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_get_with() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+
+        assert_eq!(cache.get_with(&0, false), Some(&0));
+        assert!(!referenced(&cache, &0));
+        assert_eq!(cache.get_with(&1, true), Some(&1));
+        assert!(referenced(&cache, &1));
+        assert_eq!(cache.get_with(&2, true), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(0).unwrap();
+        assert!(cache.is_disabled());
+        assert!(ClockProCache::<u64, u64>::new(2).is_err());
+        for i in 0..10 {
+            assert!(!cache.insert(i, i));
+        }
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.get_mut(&0), None);
+        assert!(!cache.contains_key(&0));
+        assert_eq!(cache.remove(&0), None);
+        assert_eq!(cache.inserted(), 0);
+    }
+
+    #[test]
+    fn test_insert_hashes_once() {
+        use std::cell::Cell;
+        use std::hash::{Hash, Hasher};
+
+        thread_local!(static HASHES: Cell<usize> = const { Cell::new(0) });
+
+        #[derive(Clone, PartialEq, Eq)]
+        struct CountedKey(u64);
+
+        impl Hash for CountedKey {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                HASHES.with(|hashes| hashes.set(hashes.get() + 1));
+                self.0.hash(state);
+            }
+        }
+
+        let hashes = || HASHES.with(|hashes| hashes.replace(0));
+        let mut cache = ClockProCache::new(3).unwrap();
+
+        // New key with room to spare
+        cache.insert(CountedKey(0), 0);
+        assert_eq!(hashes(), 1);
+
+        // Update of a resident key
+        cache.insert(CountedKey(0), 1);
+        assert_eq!(hashes(), 1);
+        assert_eq!(cache.get(&CountedKey(0)), Some(&1));
+        assert_eq!(hashes(), 1);
+
+        // New keys that require an eviction first: the key is hashed again
+        // to link it once the hands have made room
+        cache.insert(CountedKey(1), 1);
+        cache.insert(CountedKey(2), 2);
+        assert_eq!(hashes(), 2);
+        cache.insert(CountedKey(3), 3);
+        assert_eq!(hashes(), 2);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.test_len(), 1);
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..20 {
+            cache.insert(i, i);
+        }
+        assert!(cache.set_capacity(2).is_err());
+        cache.set_capacity(5).unwrap();
+        assert_eq!(cache.capacity(), 5);
+        assert!(cache.len() <= 5);
+        assert_eq!(cache.test_capacity(), 10);
+
+        cache.set_capacity(20).unwrap();
+        for i in 20..40 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 20);
+    }
+
+    #[test]
+    fn test_shrink_ghosts_with_capacity() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .shrink_ghosts_with_capacity(true)
+            .build()
+            .unwrap();
+        for i in 0..30 {
+            cache.insert(i, i);
+        }
+        let count_test = cache.test_len();
+        assert!(count_test > 4);
+        cache.set_capacity(4).unwrap();
+        assert_eq!(cache.test_capacity(), 4);
+        assert!(cache.test_len() <= 4);
+        assert!(cache.test_len() < count_test);
+        assert!(cache.len() <= 4);
+
+        // Trimming the ghosts leaves the resident entries alone
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(3)
+            .ghost_hit_promotes_to(EntryState::Cold)
+            .shrink_ghosts_with_capacity(true)
+            .build()
+            .unwrap();
+        cache.import_keys(24..29);
+        cache.set_capacity(15).unwrap();
+        cache.rebuild_with_capacity(13).unwrap();
+        cache.insert(11, 11);
+        cache.rebuild_with_capacity(13).unwrap();
+        check_invariants(&cache);
+        assert_eq!(cache.get(&11), Some(&11));
+        cache.set_capacity(3).unwrap();
+        check_invariants(&cache);
+        assert_eq!(cache.get(&11), Some(&11));
+        assert!(cache.test_len() <= cache.test_capacity());
+    }
+
+    #[test]
+    fn test_get_if() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        cache.insert(0, 10);
+        assert_eq!(cache.get_if(&0, |&v| v > 20), None);
+        assert!(!referenced(&cache, &0));
+        assert_eq!(cache.get_if(&0, |&v| v > 5), Some(&10));
+        assert!(referenced(&cache, &0));
+        assert_eq!(cache.get_if(&1, |_| true), None);
+    }
+
+    #[cfg(feature = "cold-capacity-history")]
+    #[test]
+    fn test_cold_capacity_history() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        assert_eq!(cache.cold_capacity_history(), &[4]);
+        for _ in 0..1000 {
+            for i in 0..10 {
+                cache.insert(i, i);
+            }
+        }
+        let history = cache.cold_capacity_history();
+        assert!(history.len() > 1 && history.len() <= 1024);
+        assert_eq!(*history.last().unwrap(), cache.cold_capacity);
+        assert!(history.iter().all(|&c| (1..=4).contains(&c)));
+    }
+
+    #[cfg(feature = "access-log")]
+    #[test]
+    fn test_recent_accesses() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        cache.insert(1, 1);
+        cache.get(&1);
+        cache.get(&2);
+        cache.insert(1, 2);
+        assert_eq!(cache.recent_accesses(), &[(1, false), (1, true)]);
+        for i in 0..3000 {
+            cache.get(&1);
+            cache.insert(i + 10, i);
+        }
+        let log = cache.recent_accesses();
+        assert_eq!(log.len(), 1024);
+        assert_eq!(log.last(), Some(&(3009, false)));
+        assert_eq!(log[log.len() - 2], (1, true));
+        assert_eq!(log[..2], [(1, true), (2498, false)]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let halves = |builder: fn(usize) -> super::ClockProCacheBuilder<u64, u64>| {
+            let mut a = builder(10).build().unwrap();
+            let mut b: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+            for i in 0..5 {
+                a.insert(i, i);
+                b.insert(i + 3, 100 + i + 3);
+            }
+            a.merge(b);
+            a
+        };
+
+        let mut cache = halves(ClockProCache::builder);
+        assert_eq!(cache.len(), 8);
+        for i in 0..8 {
+            let expected = if i < 5 { i } else { 100 + i };
+            assert_eq!(cache.get(&i), Some(&expected));
+        }
+
+        let mut cache = halves(|capacity| ClockProCache::builder(capacity).merge_overwrites(true));
+        assert_eq!(cache.len(), 8);
+        for i in 0..8 {
+            let expected = if i < 3 { i } else { 100 + i };
+            assert_eq!(cache.get(&i), Some(&expected));
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "The map and the slab are out of sync")]
+    fn test_desync_is_detected() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        let token = cache.map[&0];
+        cache.map.insert(0, cache.map[&1]);
+        cache.map.insert(1, token);
+        cache.get(&0);
+    }
+
+    #[test]
+    fn test_replace_if() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        cache.insert(0, 1);
+        assert_eq!(cache.replace_if(&0, 2, |&v| v == 1), Ok(Some(1)));
+        assert_eq!(cache.get(&0), Some(&2));
+        assert_eq!(cache.replace_if(&0, 3, |&v| v == 1), Err(3));
+        assert_eq!(cache.get(&0), Some(&2));
+        assert_eq!(cache.replace_if(&1, 4, |_| true), Err(4));
+    }
+
+    #[test]
+    fn test_iter_clock_order() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        assert_eq!(cache.iter_clock_order().count(), 0);
+        for i in 0..6 {
+            cache.insert(i, i);
+        }
+        let entries: Vec<_> = cache.iter_clock_order().collect();
+        assert_eq!(entries.len(), cache.len() + cache.test_len());
+        assert_eq!(
+            entries.iter().filter(|(_, value)| value.is_none()).count(),
+            cache.test_len()
+        );
+        let first = cache.slab[cache.hand_cold].as_ref().unwrap();
+        assert_eq!(entries[0].0, &first.key);
+        for (key, value) in entries {
+            if let Some(value) = value {
+                assert_eq!(key, value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_ghost_key_memory() {
+        use super::ByteSized;
+
+        let key = |i: usize| format!("{:01000}", i);
+        let key_size = key(0).byte_size();
+        let mut cache: ClockProCache<String, usize> = ClockProCache::builder(10)
+            .max_ghost_key_memory(3 * key_size)
+            .build()
+            .unwrap();
+        for i in 0..100 {
+            cache.insert(key(i), i);
+            assert!(cache.test_len() <= 3);
+            assert!(cache.ghost_key_bytes <= 3 * key_size);
+        }
+        assert!(cache.test_len() > 0);
+        assert_eq!(cache.ghost_key_bytes, cache.test_len() * key_size);
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        for i in 0..4 {
+            cache.insert(i, i);
+        }
+        assert!(cache.inspect(&10).is_none());
+
+        let ghost = (0..4).find(|i| !cache.contains_key(i)).unwrap();
+        let info = cache.inspect(&ghost).unwrap();
+        assert_eq!(info.state, EntryState::Test);
+        assert_eq!(info.value, None);
+
+        cache.insert(ghost, ghost);
+        let info = cache.inspect(&ghost).unwrap();
+        assert_eq!(info.state, EntryState::Hot);
+        assert_eq!(info.value, Some(&ghost));
+        assert!(!info.referenced);
+
+        let cold = (0..4)
+            .find(|i| cache.frequent_len() > 0 && *i != ghost)
+            .unwrap();
+        cache.get(&cold);
+        let info = cache.inspect(&cold).unwrap();
+        assert_eq!(info.state, EntryState::Cold);
+        assert!(info.referenced);
+        // Inspecting never sets the reference bit
+        assert!(!cache.inspect(&ghost).unwrap().referenced);
+    }
+
+    #[test]
+    fn test_randomized_start() {
+        let run = |seed: u64| {
+            let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+                .randomized_start(seed)
+                .build()
+                .unwrap();
+            for i in 0..100 {
+                cache.insert(i % 15, i);
+                assert!(cache.len() <= 10);
+            }
+            let mut keys: Vec<_> = (0..15).filter(|i| cache.contains_key(i)).collect();
+            keys.sort_unstable();
+            keys
+        };
+        // The same seed always yields the same cache
+        assert_eq!(run(42), run(42));
+        assert_eq!(run(7).len(), 10);
+    }
+
+    #[test]
+    fn test_insert_into() {
+        let mut cache: ClockProCache<String, String> = ClockProCache::new(3).unwrap();
+        assert!(cache.insert_into("key", "value"));
+        assert!(!cache.insert_into(String::from("key"), "other"));
+        assert_eq!(cache.get("key").map(String::as_str), Some("other"));
+    }
+
+    #[test]
+    fn test_occupancy() {
+        let mut cache: ClockProCache<u64, u64> =
+            ClockProCache::new_with_test_capacity(5, 4).unwrap();
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        assert_eq!(
+            cache.occupancy(),
+            Occupancy {
+                hot: 0,
+                cold: 2,
+                test: 0,
+                free_resident: 3,
+                free_test: 4,
+            }
+        );
+        for i in 2..8 {
+            cache.insert(i, i);
+        }
+        let occupancy = cache.occupancy();
+        assert_eq!(occupancy.hot + occupancy.cold + occupancy.free_resident, 5);
+        assert_eq!(occupancy.test + occupancy.free_test, 4);
+        assert_eq!(occupancy.test, cache.test_len());
+    }
+
+    #[test]
+    fn test_eq() {
+        let mut a: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        let mut b: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in (0..4).rev() {
+            b.insert(i, i);
+        }
+        for i in 0..4 {
+            a.insert(i, i);
+            a.get(&i);
+        }
+        assert_eq!(a, b);
+        b.insert(3, 4);
+        assert_ne!(a, b);
+        b.insert(3, 3);
+        b.insert(4, 4);
+        assert_ne!(a, b);
+        b.remove(&4);
+        assert_eq!(a, b);
+        assert_eq!(
+            format!("{:?}", ClockProCache::<u64, u64>::new(3).unwrap()),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn test_extract_if() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(20).unwrap();
+        for i in 0..30 {
+            cache.insert(i, i);
+            // Make some of the entries hot
+            if i % 4 == 0 {
+                cache.get(&i);
+            }
+        }
+        let len = cache.len();
+        let mut extracted = cache.extract_if(|key, _| key % 3 == 0);
+        extracted.sort_unstable();
+        assert!(!extracted.is_empty());
+        assert!(extracted
+            .iter()
+            .all(|(key, value)| key % 3 == 0 && key == value));
+        assert_eq!(cache.len(), len - extracted.len());
+        assert_eq!(cache.test_len() + cache.len(), cache.ring.len());
+        for i in 0..30 {
+            if i % 3 == 0 {
+                assert!(!cache.contains_key(&i));
+            }
+        }
+        for i in 30..60 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 20);
+    }
+
+    #[test]
+    fn test_eviction_low_watermark() {
+        assert!(ClockProCache::<u64, u64>::builder(10)
+            .eviction_low_watermark(11)
+            .build()
+            .is_err());
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .eviction_low_watermark(6)
+            .build()
+            .unwrap();
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 10);
+        cache.insert(10, 10);
+        assert_eq!(cache.len(), 6);
+        assert!(cache.contains_key(&10));
+        for i in 11..15 {
+            cache.insert(i, i);
+            assert_eq!(cache.len(), 7 + (i - 11) as usize);
+        }
+        cache.insert(15, 15);
+        assert_eq!(cache.len(), 6);
+
+        // The hot floor is above the watermark, which cannot be reached
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .eviction_low_watermark(2)
+            .min_hot(5)
+            .build()
+            .unwrap();
+        for _ in 0..3 {
+            for i in 0..20 {
+                cache.insert(i, i);
+                cache.get(&i);
+                check_invariants(&cache);
+            }
+        }
+        assert!(cache.frequent_len() > 0);
+        assert!(cache.len() <= 10);
+    }
+
+    #[test]
+    fn test_get_cloned_or_insert_with() {
+        use std::sync::{Arc, Mutex};
+
+        let cache = Mutex::new(ClockProCache::<u64, Arc<Vec<u64>>>::new(3).unwrap());
+        let mut value = cache
+            .lock()
+            .unwrap()
+            .get_cloned_or_insert_with(0, || Arc::new(vec![1]));
+        assert_eq!(*value, vec![1]);
+        Arc::make_mut(&mut value).push(2);
+
+        let hit = cache
+            .lock()
+            .unwrap()
+            .get_cloned_or_insert_with(0, || unreachable!());
+        assert_eq!(*hit, vec![1]);
+        assert!(cache.lock().unwrap().contains_key(&0));
+    }
+
+    #[test]
+    fn test_prune_empty_slots() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(100).unwrap();
+        for i in 0..300 {
+            cache.insert(i, i);
+        }
+        let removed = cache.extract_if(|key, _| key % 10 != 0);
+        assert!(!removed.is_empty());
+        let resident: Vec<_> = (0..300).filter(|i| cache.contains_key(i)).collect();
+        let ghosts = cache.test_len();
+        let capacity = cache.slab.capacity();
+
+        cache.prune_empty_slots();
+        assert!(cache.slab.capacity() < capacity);
+        assert_eq!(cache.slab.len(), resident.len() + ghosts);
+        assert_eq!(cache.ring.len(), resident.len() + ghosts);
+        for i in &resident {
+            assert_eq!(cache.get(i), Some(i));
+        }
+        assert_eq!(cache.test_len(), ghosts);
+
+        // The storage grows back as needed
+        for i in 300..600 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 100);
+        assert!(cache.contains_key(&599));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cache: ClockProCache<u64, u64> =
+            ClockProCache::new_with_test_capacity(3, 1).unwrap();
+        assert_eq!(cache.stats(), CacheStats::default());
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        cache.insert(0, 0);
+        cache.get(&0);
+        cache.get(&1);
+        cache.get_mut(&7);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.inserts), (2, 1, 4));
+        assert_eq!((stats.evictions, stats.ghost_removals), (0, 0));
+
+        for i in 3..6 {
+            cache.insert(i, i);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.inserts, 7);
+        assert!(stats.evictions >= 3);
+        assert_eq!(
+            stats.ghost_removals,
+            stats.evictions - cache.test_len() as u64
+        );
+    }
+
+    #[test]
+    fn test_with_test_ratio() {
+        let test_capacity = |ratio| {
+            ClockProCache::<u64, u64>::with_test_ratio(100, ratio)
+                .unwrap()
+                .test_capacity()
+        };
+        assert_eq!(test_capacity(1.0), 100);
+        assert_eq!(test_capacity(0.255), 26);
+        assert_eq!(test_capacity(2.0), 200);
+        assert_eq!(test_capacity(0.0), 1);
+        assert!(ClockProCache::<u64, u64>::with_test_ratio(100, -1.0).is_err());
+        assert!(ClockProCache::<u64, u64>::with_test_ratio(100, f64::NAN).is_err());
+        assert!(ClockProCache::<u64, u64>::with_test_ratio(100, 1e300).is_err());
+        assert!(ClockProCache::<u64, u64>::new_with_test_capacity(100, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_auto_traits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ClockProCache<u64, u64>>();
+        assert_send_sync::<ClockProCache<String, Vec<u8>>>();
+        assert_send_sync::<super::ClockProCacheBuilder<String, Vec<u8>>>();
+        assert_send_sync::<super::ClockIter<'_, String, Vec<u8>>>();
+    }
+
+    #[test]
+    fn test_auto() {
+        use super::auto_test_capacity;
+
+        assert_eq!(
+            ClockProCache::<u64, u64>::auto(100)
+                .unwrap()
+                .test_capacity(),
+            100
+        );
+        assert_eq!(auto_test_capacity(4096), 4096);
+        assert_eq!(auto_test_capacity(4096 + 8000), 5096);
+        let mut previous = 0;
+        for capacity in (3..100_000).step_by(7) {
+            let test_capacity = auto_test_capacity(capacity);
+            assert!(test_capacity >= previous);
+            assert!(test_capacity <= capacity);
+            previous = test_capacity;
+        }
+    }
+
+    #[test]
+    fn test_entry_state_or_default() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        let (state, count) = cache.entry_state_or_default(0);
+        assert_eq!(state, EntryState::Cold);
+        *count += 1;
+        let (state, count) = cache.entry_state_or_default(0);
+        assert_eq!(state, EntryState::Cold);
+        assert_eq!(*count, 1);
+        assert!(referenced(&cache, &0));
+
+        for i in 1..10 {
+            cache.insert(i, i);
+        }
+        assert!(cache.test_len() > 0);
+        let ghost = (1..10)
+            .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Test))
+            .unwrap();
+        let (state, value) = cache.entry_state_or_default(ghost);
+        assert_eq!(state, EntryState::Hot);
+        assert_eq!(*value, 0);
+    }
+
+    #[test]
+    fn test_frequency_counter_limit() {
+        assert!(ClockProCache::<u64, u64>::builder(3)
+            .frequency_counter_limit(0)
+            .build()
+            .is_err());
+
+        let mut node = super::Node {
+            key: 0u64,
+            value: Some(0u64),
+            node_type: NodeType::HOT,
+            frequency: 0,
+            #[cfg(feature = "access-count")]
+            accesses: 0,
+            generation: 0,
+            phantom_k: PhantomData,
+        };
+        for _ in 0..5 {
+            node.reference(Some(3));
+        }
+        assert_eq!(node.frequency, 3);
+        // Each sweep consumes one access, the entry is demoted on the fourth
+        assert!((0..3).all(|_| node.consume_reference()));
+        assert!(!node.consume_reference());
+
+        // Without a limit, the counter is unused and one sweep clears the bit
+        node.reference(None);
+        assert!(node.consume_reference());
+        assert!(!node.consume_reference());
+
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .frequency_counter_limit(3)
+            .build()
+            .unwrap();
+        for i in 0..1000 {
+            let key = i % 7 + (i % 3) * 10;
+            if cache.get(&key).is_none() {
+                cache.insert(key, key);
+            }
+        }
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.recent_len() + cache.frequent_len(), 10);
+    }
+
+    #[test]
+    fn test_remove_ghost() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        for i in 0..6 {
+            cache.insert(i, i);
+        }
+        let ghost = (0..6)
+            .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Test))
+            .unwrap();
+        let test_len = cache.test_len();
+        assert!(!cache.remove_ghost(&5));
+        assert!(cache.remove_ghost(&ghost));
+        assert!(!cache.remove_ghost(&ghost));
+        assert_eq!(cache.test_len(), test_len - 1);
+        assert!(cache.inspect(&ghost).is_none());
+
+        // A ghost that is forgotten comes back as a plain cold entry
+        cache.insert(ghost, ghost);
+        assert_eq!(cache.inspect(&ghost).unwrap().state, EntryState::Cold);
+
+        // `remove` on a ghost key keeps the test count in sync too
+        let ghost = (0..6)
+            .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Test))
+            .unwrap();
+        let test_len = cache.test_len();
+        assert_eq!(cache.remove(&ghost), None);
+        assert_eq!(cache.test_len(), test_len - 1);
+    }
+
+    #[test]
+    fn test_rebuild_with_capacity() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
+        }
+        // Ghost hits come back as hot entries
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        let hot: Vec<u64> = (0..13)
+            .filter(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Hot))
+            .collect();
+        assert!(!hot.is_empty() && hot.len() < cache.len());
+        assert!(cache.rebuild_with_capacity(2).is_err());
+
+        cache.rebuild_with_capacity(hot.len().max(3)).unwrap();
+        assert_eq!(cache.len(), hot.len().max(3));
+        for key in &hot {
+            assert!(cache.contains_key(key));
+        }
+        assert_eq!(cache.recent_len() + cache.frequent_len(), cache.len());
+
+        cache.rebuild_with_capacity(20).unwrap();
+        assert_eq!(cache.capacity(), 20);
+        for i in 20..40 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 20);
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut individual: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        let mut batched: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..8 {
+            individual.insert(i, i);
+            *individual.get_mut(&i).unwrap() += 1;
+        }
+        individual.remove(&3);
+        {
+            let mut batch = batched.batch();
+            for i in 0..8 {
+                batch.insert(i, i);
+                *batch.get_mut(&i).unwrap() += 1;
+            }
+            batch.remove(&3);
+        }
+        assert_eq!(individual, batched);
+        assert_eq!(individual.occupancy(), batched.occupancy());
+
+        // Past the capacity, nothing is evicted until the batch ends
+        {
+            let mut batch = batched.batch();
+            for i in 100..120 {
+                batch.insert(i, i);
+            }
+            for i in 100..120 {
+                assert_eq!(batch.get_mut(&i).copied(), Some(i));
+            }
+        }
+        assert_eq!(batched.len(), 10);
+        assert_eq!(batched.recent_len() + batched.frequent_len(), 10);
+        for i in 200..300 {
+            batched.insert(i, i);
+            assert!(batched.len() <= 10);
+        }
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let run = || {
+            let mut cache: ClockProCache<u64, u64, _> = ClockProCache::deterministic(10).unwrap();
+            for i in 0..100 {
+                cache.insert(i * 7 % 31, i);
+                cache.get(&(i % 5));
+            }
+            // The map iteration order depends on the hasher keys
+            let map_order: Vec<u64> = cache.map.keys().copied().collect();
+            let clock_order: Vec<_> = cache
+                .iter_clock_order()
+                .map(|(key, value)| (*key, value.copied()))
+                .collect();
+            (map_order, clock_order)
+        };
+        assert_eq!(run(), run());
+
+        let mut cache = ClockProCache::deterministic(3).unwrap();
+        cache.insert("a", 1);
+        let mut other: ClockProCache<&str, u32> = ClockProCache::new(3).unwrap();
+        other.merge(cache);
+        assert_eq!(other.get(&"a"), Some(&1));
+    }
+
+    #[test]
+    fn test_cold_capacity_bounds_at_capacity_3() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        let mut hot_seen = false;
+        for round in 0..200 {
+            // Cycling over more keys than the cache holds turns most
+            // insertions into ghost hits
+            for i in 0..5 {
+                cache.insert(i, round);
+                assert!((1..=2).contains(&cache.cold_capacity));
+                assert!(cache.len() <= 3);
+                hot_seen |= cache.frequent_len() > 0;
+            }
+        }
+        assert!(hot_seen);
+
+        // Once the hot set stops being refreshed, the cold hand can still
+        // reclaim its entries
+        for i in 100..200 {
+            cache.insert(i, i);
+        }
+        assert!(cache.recent_len() > 0);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[cfg(feature = "access-count")]
+    #[test]
+    fn test_access_count() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        cache.insert(0, 0);
+        assert_eq!(cache.access_count(&0), Some(0));
+        cache.get(&0);
+        cache.get_mut(&0);
+        cache.get_with(&0, false);
+        assert_eq!(cache.access_count(&0), Some(3));
+        cache.insert(0, 1);
+        assert_eq!(cache.access_count(&0), Some(3));
+        assert_eq!(cache.access_count(&1), None);
+    }
+
+    #[cfg(feature = "load-timing")]
+    #[test]
+    fn test_load_timing() {
+        use std::time::Duration;
+
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        let slow = || {
+            std::thread::sleep(Duration::from_millis(5));
+            1
+        };
+        assert_eq!(*cache.get_or_insert_with(0, slow), 1);
+        let stats = cache.stats();
+        assert_eq!(stats.load_count, 1);
+        assert!(stats.total_load_time >= Duration::from_millis(5));
+
+        assert_eq!(*cache.get_or_insert_with(0, slow), 1);
+        assert_eq!(cache.get_cloned_or_insert_with(0, slow), 1);
+        assert_eq!(cache.get_or_maybe_insert_with(0, || Some(2)), Some(&1));
+        assert_eq!(cache.stats().load_count, 1);
+        assert_eq!(cache.stats().total_load_time, stats.total_load_time);
+
+        assert_eq!(cache.get_or_maybe_insert_with(1, || None), None);
+        assert_eq!(cache.get_cloned_or_insert_with(2, || 2), 2);
+        assert_eq!(cache.stats().load_count, 3);
+    }
+
+    #[cfg(feature = "access-count")]
+    #[test]
+    fn test_is_single_access() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        cache.insert(0, 0);
+        cache.insert(1, 1);
+        assert_eq!(cache.is_single_access(&0), Some(true));
+        cache.get(&1);
+        cache.get(&1);
+        assert_eq!(cache.is_single_access(&0), Some(true));
+        assert_eq!(cache.is_single_access(&1), Some(false));
+        cache.insert(0, 10);
+        assert_eq!(cache.is_single_access(&0), Some(true));
+        assert_eq!(cache.is_single_access(&2), None);
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..15 {
+            cache.insert(i, i);
+        }
+        let len = cache.len();
+        let odd = cache.split_off(|key| key % 2 == 1);
+        assert_eq!(odd.capacity(), 10);
+        assert_eq!(odd.test_capacity(), 10);
+        assert_eq!(cache.len() + odd.len(), len);
+        assert_eq!(odd.len(), odd.recent_len());
+        assert_eq!(cache.len(), cache.recent_len() + cache.frequent_len());
+        for i in 0..15 {
+            assert!(
+                !(cache.inspect(&i).and_then(|info| info.value).is_some()
+                    && odd.inspect(&i).is_some())
+            );
+            if let Some(info) = odd.inspect(&i) {
+                assert_eq!(i % 2, 1);
+                assert_eq!(info.state, EntryState::Cold);
+            }
+        }
+
+        // Resizing does not revisit the options checked at construction
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .eviction_low_watermark(8)
+            .min_hot(6)
+            .adaptive_test_capacity(10)
+            .build()
+            .unwrap();
+        for i in 0..15 {
+            cache.insert(i, i);
+        }
+        cache.set_capacity(5).unwrap();
+        cache.set_test_capacity(20).unwrap();
+        let mut odd = cache.split_off(|key| key % 2 == 1);
+        assert_eq!(odd.capacity(), 5);
+        assert_eq!(odd.test_capacity(), 20);
+        for i in 20..40 {
+            odd.insert(i, i);
+            check_invariants(&odd);
+        }
+        assert!(odd.len() <= 5);
+
+        let mut disabled: ClockProCache<u64, u64> = ClockProCache::new(0).unwrap();
+        assert!(disabled.split_off(|_| true).is_disabled());
+    }
+
+    #[cfg(any(debug_assertions, feature = "checked-counts"))]
+    #[test]
+    #[should_panic(expected = "self.count_test underflowed")]
+    fn test_checked_counts() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        for i in 0..4 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.test_len(), 1);
+        cache.count_test = 0;
+        for i in 0..4 {
+            cache.remove_ghost(&i);
+        }
+    }
+
+    #[test]
+    fn test_clock_policy() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(5)
+            .eviction_policy(EvictionPolicy::Clock)
+            .build()
+            .unwrap();
+        for i in 0..5 {
+            cache.insert(i, i);
+        }
+        for i in [0, 1, 2, 4] {
+            cache.get(&i);
+        }
+        cache.insert(5, 5);
+        // Referenced entries got a second chance
+        assert!(!cache.contains_key(&3));
+        assert!([0, 1, 2, 4].iter().all(|i| cache.contains_key(i)));
+        for i in 10..100 {
+            cache.insert(i, i);
+            assert_eq!(cache.frequent_len(), 0);
+            assert_eq!(cache.test_len(), 0);
+            assert!(cache.len() <= 5);
+        }
+        assert_eq!(cache.inspect(&3).map(|info| info.state), None);
+        cache.set_capacity(3).unwrap();
+        assert_eq!(cache.len(), 3);
+
+        // With a slot reserved, the eviction goes down to the hot target
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(4)
+            .eviction_policy(EvictionPolicy::Clock)
+            .eviction_low_watermark(1)
+            .build()
+            .unwrap();
+        let _reservation = cache.reserve_slot(100).unwrap();
+        for i in 0..10 {
+            cache.insert(i, i);
+            check_invariants(&cache);
+        }
+        assert_eq!(cache.get(&9), Some(&9));
+    }
+
+    #[test]
+    fn test_replay_trace() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        let mut loads = 0;
+        let replay = cache.replay_trace((0..100).map(|i| i % 5), |&key| {
+            loads += 1;
+            key
+        });
+        assert_eq!(replay.misses, 5);
+        assert_eq!(replay.hits, 95);
+        assert_eq!(replay.evictions, 0);
+        assert_eq!(loads, 5);
+        assert!((replay.hit_ratio() - 0.95).abs() < 1e-9);
+
+        let replay = cache.replay_trace(100..120, |&key| key);
+        assert_eq!(replay.hits, 0);
+        assert_eq!(replay.evictions, 15);
+        assert_eq!(
+            ClockProCache::<u64, u64>::new(3)
+                .unwrap()
+                .replay_trace(None, |_| 0)
+                .hit_ratio(),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_negative_bloom() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .negative_bloom(20)
+            .build()
+            .unwrap();
+        for i in 0..1000 {
+            cache.insert(i, i);
+            assert_eq!(cache.get(&i), Some(&i));
+            for j in i.saturating_sub(20)..=i {
+                assert_eq!(cache.inspect(&j).is_some(), cache.map.contains_key(&j));
+            }
+        }
+        let bloom = cache.bloom.as_ref().unwrap();
+        let false_positives = (1_000_000..1_010_000)
+            .filter(|key| bloom.may_contain(cache.map.hasher().hash_one(key)))
+            .count();
+        assert!(false_positives < 1000);
+        assert_eq!(cache.get(&1_000_000), None);
+    }
+
+    #[test]
+    fn test_map_values() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..15 {
+            cache.insert(i, i);
+        }
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        cache.get(&14);
+        let strings = cache.map_values(|value| value.to_string());
+        assert_eq!(strings.occupancy(), cache.occupancy());
+        for i in 0..15 {
+            let (source, mapped) = (cache.inspect(&i), strings.inspect(&i));
+            assert_eq!(source.is_some(), mapped.is_some());
+            if let (Some(source), Some(mapped)) = (source, mapped) {
+                assert_eq!(source.state, mapped.state);
+                assert_eq!(source.referenced, mapped.referenced);
+                assert_eq!(source.value.map(|v| v.to_string()).as_ref(), mapped.value);
+            }
+        }
+        let order: Vec<u64> = cache.iter_clock_order().map(|(key, _)| *key).collect();
+        let mapped_order: Vec<u64> = strings.iter_clock_order().map(|(key, _)| *key).collect();
+        assert_eq!(order, mapped_order);
+    }
+
+    #[test]
+    fn test_on_capacity_change() {
+        use std::sync::{Arc, Mutex};
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        let recorded = changes.clone();
+        cache.on_capacity_change(move |change| recorded.lock().unwrap().push(change));
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        cache.set_capacity(6).unwrap();
+        assert!(cache.set_capacity(2).is_err());
+        cache.set_test_capacity(2).unwrap();
+        assert!(cache.test_len() <= 2);
+
+        let changes = changes.lock().unwrap();
+        assert_eq!(
+            changes[0],
+            CapacityChange {
+                old_capacity: 10,
+                new_capacity: 6,
+                old_test_capacity: 10,
+                new_test_capacity: 10,
+                evicted: 4,
+                ghosts_removed: 0,
+            }
+        );
+        assert_eq!(changes[1].new_test_capacity, 2);
+        assert_eq!(changes[1].ghosts_removed, 2);
+        assert_eq!(changes.len(), 2);
+    }
+
+    #[test]
+    fn test_set_test_capacity() {
+        // Dropping ghosts leaves the resident entries alone
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..5 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.import_keys(100..105), 5);
+        cache.set_test_capacity(0).unwrap();
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.test_len(), 0);
+        check_invariants(&cache);
+        for i in 0..5 {
+            assert_eq!(cache.get(&i), Some(&i));
+        }
+
+        // Down to a couple of nodes in the ring
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        for i in 0..6 {
+            cache.insert(i, i);
+        }
+        for i in [0, 3, 4, 5] {
+            cache.remove(&i);
+        }
+        let len = cache.len();
+        cache.set_test_capacity(0).unwrap();
+        assert_eq!(cache.len(), len);
+        assert_eq!(cache.test_len(), 0);
+        check_invariants(&cache);
+        for i in 10..20 {
+            cache.insert(i, i);
+            check_invariants(&cache);
+        }
+    }
+
+    #[test]
+    fn test_try_reserve() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        cache.try_reserve(100).unwrap();
+        assert!(cache.map.capacity() >= 100);
+        assert!(cache.slab.capacity() >= cache.slab.len() + 100);
+        assert!(cache.try_reserve(usize::MAX).is_err());
+        cache.insert(0, 0);
+        assert_eq!(cache.get(&0), Some(&0));
+    }
+
+    #[test]
+    fn test_get_key_value() {
+        let mut cache: ClockProCache<String, u64> = ClockProCache::new(3).unwrap();
+        cache.insert(String::from("key"), 1);
+        let (stored, value) = cache.get_key_value("key").unwrap();
+        assert_eq!((stored, *value), (&String::from("key"), 1));
+        assert!(cache.inspect("key").unwrap().referenced);
+        assert_eq!(cache.get_key_value("other"), None);
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[test]
+    fn test_boxed_values() {
+        let mut cache: BoxedClockProCache<u64, [u8; 4096]> = ClockProCache::new(3).unwrap();
+        cache.insert(0, Box::new([1; 4096]));
+        assert_eq!(cache.get(&0).map(|value| value[0]), Some(1));
+        assert!(std::mem::size_of_val(&cache.slab[0]) < 4096);
+    }
+
+    #[test]
+    fn test_retain_with_state() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
+        }
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        let hot: Vec<u64> = (0..13)
+            .filter(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Hot))
+            .collect();
+        assert!(!hot.is_empty());
+
+        // Drop every even entry, unless it is hot
+        cache.retain_with_state(|key, _, state| state == EntryState::Hot || key % 2 == 1);
+        for key in &hot {
+            assert!(cache.contains_key(key));
+        }
+        for i in 0..13 {
+            if let Some(info) = cache.inspect(&i).filter(|info| info.value.is_some()) {
+                assert!(info.state == EntryState::Hot || i % 2 == 1);
+            }
+        }
+        assert_eq!(cache.len(), cache.recent_len() + cache.frequent_len());
+
+        cache.retain(|_, value| *value < 5);
+        assert!(cache.resident().all(|(_, value)| *value < 5));
+    }
+
+    #[test]
+    fn test_hits_per_state() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
+        }
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        let (mut hot, mut cold) = (0, 0);
+        for i in 0..13 {
+            match cache
+                .inspect(&i)
+                .filter(|info| info.value.is_some())
+                .map(|info| info.state)
+            {
+                Some(EntryState::Hot) => hot += 1,
+                Some(EntryState::Cold) => cold += 1,
+                _ => {}
+            }
+            cache.get(&i);
+        }
+        let stats = cache.stats();
+        assert!(hot > 0 && cold > 0);
+        assert_eq!((stats.hot_hits, stats.cold_hits), (hot, cold));
+        assert_eq!(stats.hits, stats.hot_hits + stats.cold_hits);
+    }
+
+    #[test]
+    fn test_promote() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        for i in 0..5 {
+            cache.insert(i, i);
+        }
+        assert!(!cache.promote(&10));
+        for i in 0..4 {
+            assert!(cache.promote(&i));
+            assert_eq!(cache.inspect(&i).unwrap().state, EntryState::Hot);
+            assert!(cache.count_hot <= cache.capacity - cache.cold_capacity);
+            assert!(cache.cold_capacity >= 1);
+            assert_eq!(cache.len(), 5);
+            assert_eq!(cache.recent_len() + cache.frequent_len(), 5);
+        }
+        // The last resident slot stays cold
+        assert!(!cache.promote(&4));
+        assert_eq!(cache.frequent_len(), 4);
+        for i in 5..50 {
+            cache.insert(i, i);
+            assert!(cache.count_hot <= cache.capacity - cache.cold_capacity);
+        }
+        assert_eq!(cache.len(), 5);
+
+        // The CLOCK hand could not evict hot entries when shrinking
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .eviction_policy(EvictionPolicy::Clock)
+            .build()
+            .unwrap();
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        for i in 0..10 {
+            assert!(!cache.promote(&i));
+        }
+        assert_eq!(cache.frequent_len(), 0);
+        cache.set_capacity(3).unwrap();
+        assert_eq!(cache.len(), 3);
+        check_invariants(&cache);
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        for i in 0..5 {
+            cache.insert(i, i);
+        }
+        assert!(!cache.pin(&10));
+        for i in 0..4 {
+            assert!(cache.pin(&i));
+        }
+        assert!(!cache.pin(&0));
+        // At least one slot has to stay evictable
+        assert!(!cache.pin(&4));
+        assert_eq!(cache.pinned_len(), 4);
+        assert_eq!(cache.len(), 5);
+        for i in 10..100 {
+            cache.insert(i, i);
+            assert_eq!(cache.len(), 5);
+            assert!((0..4).all(|key| cache.contains_key(&key)));
+        }
+        assert!(cache.set_capacity(4).is_err());
+
+        assert!(cache.unpin(&0));
+        assert!(!cache.unpin(&0));
+        assert_eq!(cache.pinned_len(), 3);
+        for i in 100..200 {
+            cache.insert(i, i);
+        }
+        // The unpinned entry is back under the control of the hands
+        assert_eq!(cache.recent_len() + cache.frequent_len(), 2);
+        assert!(cache.rebuild_with_capacity(4).is_ok());
+        assert!(cache.rebuild_with_capacity(3).is_err());
+        assert!(!cache.contains_key(&0));
+        assert!((1..4).all(|key| cache.contains_key(&key)));
+        assert_eq!(cache.remove(&1), Some(1));
+        assert_eq!(cache.pinned_len(), 2);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_storage_grows_on_demand() {
+        let mut cache: ClockProCache<u64, u64> =
+            ClockProCache::new_with_test_capacity(3, 3).unwrap();
+        assert!(cache.slab.is_empty());
+        for round in 0..50 {
+            // Fill up to the boundary, then go one past it with a new key
+            // and with a ghost hit
+            for i in 0..4 {
+                cache.insert(round * 4 + i, i);
+            }
+            cache.insert(round * 4, round);
+            assert_eq!(cache.len(), 3);
+            assert!(cache.count_test <= 3);
+            assert_eq!(cache.map.len(), cache.len() + cache.count_test);
+        }
+        assert!(cache.slab.len() <= 3 + 3 + 1);
+
+        assert!(cache.set_capacity(100).is_ok());
+        for i in 0..1000 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 100);
+        assert!(cache.slab.len() <= 100 + cache.test_capacity + 1);
+    }
+
+    #[test]
+    fn test_insert_and_report_eviction() {
+        for policy in [EvictionPolicy::ClockPro, EvictionPolicy::Clock] {
+            let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(5)
+                .eviction_policy(policy)
+                .build()
+                .unwrap();
+            for i in 0..5 {
+                assert_eq!(cache.insert_and_report_eviction(i, i), None);
+            }
+            assert_eq!(cache.insert_and_report_eviction(0, 10), None);
+            for i in 5..50 {
+                let evicted = cache.insert_and_report_eviction(i, i).unwrap();
+                assert!(!cache.contains_key(&evicted));
+                assert!(evicted < i);
+                assert_eq!(cache.len(), 5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_clear_with_callback() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..20 {
+            cache.insert(i, i * 2);
+        }
+        assert!(cache.pin(&19));
+        let mut cleared = Vec::new();
+        cache.clear_with_callback(|key, value| cleared.push((key, value)));
+        assert_eq!(cleared.len(), 10);
+        assert!(cleared.iter().all(|&(key, value)| value == key * 2));
+        assert!(cleared.contains(&(19, 38)));
+        assert!(cache.is_empty());
+        assert_eq!(cache.test_len(), 0);
+        assert_eq!(cache.pinned_len(), 0);
+        assert!(!cache.contains_key(&19));
+
+        for i in 0..20 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.get(&19), Some(&19));
+    }
+
+    #[test]
+    fn test_get_weighted() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .frequency_counter_limit(4)
+            .build()
+            .unwrap();
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.get_weighted(&0, 3), Some(&0));
+        assert_eq!(cache.get_weighted(&1, 200), Some(&1));
+        assert_eq!(cache.get_weighted(&2, 0), Some(&2));
+        assert_eq!(cache.get_weighted(&3, 1), None);
+        let frequency = |cache: &ClockProCache<u64, u64>, key| {
+            let token = *cache.map.get(&key).unwrap();
+            cache.slab[token].as_ref().unwrap().frequency
+        };
+        assert_eq!(frequency(&cache, 0), 3);
+        assert_eq!(frequency(&cache, 1), 4);
+        assert_eq!(frequency(&cache, 2), 0);
+        assert!(!referenced(&cache, &2));
+    }
+
+    #[test]
+    fn test_export_import_keys() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
+        }
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        let keys = cache.export_keys();
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys, cache.export_keys());
+        let hot = cache.frequent_len();
+        assert!(hot > 0);
+        assert!(keys
+            .iter()
+            .enumerate()
+            .all(|(i, key)| (cache.inspect(key).unwrap().state == EntryState::Hot) == (i < hot)));
+
+        let mut warm: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        warm.insert(keys[0], 0);
+        assert_eq!(warm.import_keys(keys.iter().copied()), 9);
+        assert_eq!(warm.test_len(), 9);
+        assert_eq!(warm.len(), 1);
+        for &key in &keys[1..3] {
+            warm.insert(key, key);
+        }
+        assert_eq!(warm.frequent_len(), 2);
+
+        let mut small: ClockProCache<u64, u64> =
+            ClockProCache::new_with_test_capacity(10, 4).unwrap();
+        assert_eq!(small.import_keys(keys), 4);
+        assert_eq!(small.test_len(), 4);
+
+        // Trimming the imported ghosts leaves the resident entries alone
+        let mut partial: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..4 {
+            partial.insert(i, i);
+        }
+        assert_eq!(partial.import_keys(100..120), 10);
+        partial.set_test_capacity(3).unwrap();
+        assert_eq!(partial.len(), 4);
+        assert_eq!(partial.test_len(), 3);
+        check_invariants(&partial);
+        partial.set_test_capacity(0).unwrap();
+        assert_eq!(partial.len(), 4);
+        assert_eq!(partial.total_tracked(), 4);
+        check_invariants(&partial);
+
+        let mut clock: ClockProCache<u64, u64> = ClockProCache::builder(5)
+            .eviction_policy(EvictionPolicy::Clock)
+            .build()
+            .unwrap();
+        assert_eq!(clock.import_keys(0..5), 0);
+        assert_eq!(clock.test_len(), 0);
+        for i in (0..5).chain(10..20) {
+            clock.insert(i, i);
+        }
+        assert_eq!(clock.len(), 5);
+        assert_eq!(clock.frequent_len(), 0);
+    }
+
+    #[test]
+    fn test_entry_and_modify_with_state() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
+        }
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        let bump = |cache: &mut ClockProCache<u64, u64>, key| {
+            *cache
+                .entry(key)
+                .and_modify_with_state(|value, state| match state {
+                    EntryState::Hot => *value += 100,
+                    _ => *value += 1,
+                })
+                .or_insert(1000)
+        };
+        let find = |cache: &ClockProCache<u64, u64>, state| {
+            (0..13)
+                .find(|key| cache.inspect(key).map(|info| info.state) == Some(state))
+                .unwrap()
+        };
+        let hot = find(&cache, EntryState::Hot);
+        let cold = find(&cache, EntryState::Cold);
+        assert_eq!(bump(&mut cache, hot), hot + 100);
+        assert_eq!(bump(&mut cache, cold), cold + 1);
+        assert_eq!(
+            cache
+                .entry(cold)
+                .and_modify(|value| *value = 0)
+                .or_insert(1),
+            &0
+        );
+        assert_eq!(bump(&mut cache, 50), 1000);
+        assert_eq!(bump(&mut cache, 50), 1001);
+        assert_eq!(cache.get(&hot), Some(&(hot + 100)));
+        assert_eq!(cache.len(), 10);
+    }
+
+    #[test]
+    fn test_extend_bounded() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        cache.insert(100, 100);
+        let rest = cache.extend_bounded((0..20).map(|i| (i, i)));
+        assert_eq!(
+            rest.collect::<Vec<_>>(),
+            (9..20).map(|i| (i, i)).collect::<Vec<_>>()
+        );
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.stats().evictions, 0);
+        assert!((0..9).all(|key| cache.contains_key(&key)));
+
+        assert_eq!(cache.extend_bounded(vec![(30, 30)]).len(), 1);
+        let mut small: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        assert_eq!(small.extend_bounded(vec![(1, 1), (1, 2)]).len(), 0);
+        assert_eq!(small.get(&1), Some(&2));
+    }
+
+    #[test]
+    fn test_get_detailed() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        let ghost = (0..10)
+            .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Test))
+            .unwrap();
+        let hit = (0..10)
+            .find(|key| cache.inspect(key).map(|info| info.value.is_some()) == Some(true))
+            .unwrap();
+        assert_eq!(cache.get_detailed(&hit), GetResult::Hit(&hit));
+        assert!(referenced(&cache, &hit));
+        assert_eq!(cache.get_detailed(&ghost), GetResult::Ghost);
+        assert_eq!(cache.get_detailed(&100), GetResult::Miss);
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 2);
+    }
+
+    #[test]
+    fn test_insert_if_changed() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        assert!(cache.insert_if_changed(1, 1));
+        assert!(!referenced(&cache, &1));
+        assert!(!cache.insert_if_changed(1, 1));
+        assert!(!referenced(&cache, &1));
+        assert_eq!(cache.stats().inserts, 1);
+        assert!(cache.insert_if_changed(1, 2));
+        assert!(referenced(&cache, &1));
+        assert_eq!(cache.get(&1), Some(&2));
+        assert_eq!(cache.stats().inserts, 2);
+        assert_eq!(cache.len(), 1);
+
+        let mut disabled: ClockProCache<u64, u64> = ClockProCache::new(0).unwrap();
+        assert!(!disabled.insert_if_changed(1, 1));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn test_adaptive_test_capacity() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(50)
+            .test_capacity(10)
+            .adaptive_test_capacity(200)
+            .build()
+            .unwrap();
+        // A loop slightly larger than the cache keeps hitting ghosts
+        for _ in 0..100 {
+            for i in 0..55 {
+                if cache.get(&i).is_none() {
+                    cache.insert(i, i);
+                }
+            }
+        }
+        assert_eq!(cache.test_capacity(), 200);
+        assert!(cache.test_len() <= 200);
+
+        // A scan never comes back to its ghosts
+        for i in 1000..20000 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.test_capacity(), 12);
+        assert!(cache.test_len() <= 12);
+
+        assert!(ClockProCache::<u64, u64>::builder(50)
+            .adaptive_test_capacity(10)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_get_or_maybe_insert_with() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        for i in 0..8 {
+            cache.insert(i, i);
+        }
+        let ghost = (0..8)
+            .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Test))
+            .unwrap();
+        let before = (
+            cache.occupancy(),
+            cache.hand_hot,
+            cache.hand_cold,
+            cache.hand_test,
+        );
+        assert_eq!(cache.get_or_maybe_insert_with(ghost, || None), None);
+        assert_eq!(cache.get_or_maybe_insert_with(100, || None), None);
+        assert_eq!(
+            before,
+            (
+                cache.occupancy(),
+                cache.hand_hot,
+                cache.hand_cold,
+                cache.hand_test
+            )
+        );
+        assert_eq!(cache.inspect(&ghost).unwrap().state, EntryState::Test);
+
+        assert_eq!(cache.get_or_maybe_insert_with(100, || Some(1)), Some(&1));
+        assert_eq!(cache.get_or_maybe_insert_with(100, || Some(2)), Some(&1));
+        assert_eq!(cache.get_or_maybe_insert_with(ghost, || Some(3)), Some(&3));
+        assert_eq!(cache.inspect(&ghost).unwrap().state, EntryState::Hot);
+
+        let mut disabled: ClockProCache<u64, u64> = ClockProCache::new(0).unwrap();
+        assert_eq!(disabled.get_or_maybe_insert_with(1, || Some(1)), None);
+    }
+
+    #[test]
+    fn test_total_tracked() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        assert_eq!(cache.total_tracked(), 0);
+        for i in 0..8 {
+            cache.insert(i, i);
+        }
+        assert!(cache.pin(&7));
+        assert_eq!(cache.total_tracked(), 8);
+        assert_eq!(cache.total_tracked(), cache.map.len());
+        for i in 8..100 {
+            cache.insert(i, i);
+            assert_eq!(cache.total_tracked(), cache.len() + cache.test_len());
+        }
+        assert_eq!(cache.total_tracked(), 10);
+    }
+
+    #[test]
+    fn test_peek_mut_guard() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        cache.insert(1, 1);
+        assert!(cache.peek_mut_guard(&2).is_none());
+        {
+            let guard = cache.peek_mut_guard(&1).unwrap();
+            assert_eq!(*guard, 1);
+        }
+        assert!(!referenced(&cache, &1));
+        {
+            let mut guard = cache.peek_mut_guard(&1).unwrap();
+            *guard += 1;
+        }
+        assert!(referenced(&cache, &1));
+        assert_eq!(cache.get(&1), Some(&2));
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn test_pairs_round_trip() {
+        let cache: ClockProCache<u64, u64> =
+            ClockProCache::from_pairs_iter(10, (0..10).map(|i| (i, i * 3))).unwrap();
+        assert_eq!(cache.len(), 10);
+        let mut pairs: Vec<_> = cache.export_pairs().collect();
+        pairs.sort_unstable();
+        assert_eq!(pairs, (0..10).map(|i| (i, i * 3)).collect::<Vec<_>>());
+
+        let mut cache = ClockProCache::from_pairs_iter(5, pairs).unwrap();
+        assert_eq!(cache.len(), 5);
+        assert!(cache.test_len() > 0);
+        let resident: Vec<_> = (0..10).filter(|key| cache.contains_key(key)).collect();
+        let mut pairs: Vec<_> = cache.export_pairs().collect();
+        pairs.sort_unstable();
+        assert_eq!(
+            pairs,
+            resident.iter().map(|&i| (i, i * 3)).collect::<Vec<_>>()
+        );
+        assert!(ClockProCache::<u64, u64>::from_pairs_iter(2, None).is_err());
+    }
+
+    #[test]
+    fn test_shrink_to() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(100).unwrap();
+        for i in 0..200 {
+            cache.insert(i, i);
+        }
+        let kept: Vec<_> = (0..200)
+            .filter(|key| cache.contains_key(key))
+            .take(20)
+            .collect();
+        cache.retain(|key, _| kept.contains(key));
+        for i in 0..200 {
+            cache.remove_ghost(&i);
+        }
+        assert_eq!(cache.total_tracked(), 20);
+        let (slab_capacity, map_capacity) = (cache.slab.capacity(), cache.map.capacity());
+        cache.shrink_to(50);
+        assert!(cache.slab.capacity() >= 50 && cache.slab.capacity() < slab_capacity);
+        assert!(cache.map.capacity() >= 50 && cache.map.capacity() < map_capacity);
+        assert_eq!(cache.capacity(), 100);
+        assert!(kept.iter().all(|key| cache.get(key) == Some(key)));
+        cache.shrink_to(0);
+        assert_eq!(cache.slab.capacity(), 20);
+        assert!(kept.iter().all(|key| cache.get(key) == Some(key)));
+    }
+
+    #[test]
+    fn test_validate_and_repair() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        for i in 0..8 {
+            cache.insert(i, i);
+        }
+        assert!(cache.pin(&7));
+        cache.remove(&0);
+        assert!(cache.validate_and_repair().is_clean());
+
+        let occupancy = cache.occupancy();
+        cache.count_cold += 2;
+        cache.count_test -= 1;
+        let report = cache.validate_and_repair();
+        assert_eq!(
+            report,
+            RepairReport {
+                counts_fixed: true,
+                ..RepairReport::default()
+            }
+        );
+        assert_eq!(cache.occupancy(), occupancy);
+
+        let token = cache.map[&7];
+        cache.map.insert(7, cache.map[&6]);
+        cache.map.insert(6, token);
+        cache.map.insert(100, token);
+        let report = cache.validate_and_repair();
+        assert_eq!(report.stale_map_entries, 3);
+        assert_eq!(report.relinked_nodes, 2);
+        assert!(!report.ring_mismatch);
+        assert!(cache.validate_and_repair().is_clean());
+        assert_eq!(cache.get(&7), Some(&7));
+        assert!(!cache.contains_key(&100));
+        assert!(!cache.contains_key(&0));
+        assert_eq!(cache.total_tracked(), 7);
+    }
+
+    #[test]
+    fn test_get_reporting_promotion() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
+        }
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        let find = |cache: &ClockProCache<u64, u64>, state| {
+            (0..13)
+                .find(|key| cache.inspect(key).map(|info| info.state) == Some(state))
+                .unwrap()
+        };
+        let (hot, cold) = (
+            find(&cache, EntryState::Hot),
+            find(&cache, EntryState::Cold),
+        );
+        assert_eq!(cache.get_reporting_promotion(&hot), Some((&hot, false)));
+        assert_eq!(cache.get_reporting_promotion(&cold), Some((&cold, true)));
+        assert!(cache.pin(&cold));
+        assert_eq!(cache.get_reporting_promotion(&cold), Some((&cold, false)));
+        assert_eq!(cache.get_reporting_promotion(&100), None);
+
+        let mut clock: ClockProCache<u64, u64> = ClockProCache::builder(10)
+            .eviction_policy(EvictionPolicy::Clock)
+            .build()
+            .unwrap();
+        clock.insert(1, 1);
+        assert_eq!(clock.get_reporting_promotion(&1), Some((&1, false)));
+    }
+
+    #[test]
+    fn test_min_hot() {
+        fn churn(mut cache: ClockProCache<u64, u64>) -> usize {
+            for i in 0..10 {
+                cache.insert(i, i);
+            }
+            for i in 0..6 {
+                assert!(cache.promote(&i));
+            }
+            // A short loop of new keys keeps hitting ghosts, which grows the
+            // cold capacity at the expense of the hot set
+            let mut min = usize::MAX;
+            for _ in 0..50 {
+                for i in 100..108 {
+                    if cache.get(&i).is_none() {
+                        cache.insert(i, i);
+                    }
+                    min = min.min(cache.frequent_len());
+                    assert_eq!(cache.len(), 10);
                 }
             }
+            min
         }
-        if run_hand_test {
-            while self.count_test > self.test_capacity {
-                self.run_hand_test();
+        assert!(churn(ClockProCache::new(10).unwrap()) < 5);
+        assert!(churn(ClockProCache::builder(10).min_hot(5).build().unwrap()) >= 5);
+        assert!(ClockProCache::<u64, u64>::builder(10)
+            .min_hot(10)
+            .build()
+            .is_err());
+
+        // A reserved slot does not take the last hot slot away
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(4)
+            .test_capacity(0)
+            .eviction_low_watermark(1)
+            .build()
+            .unwrap();
+        let _first = cache.reserve_slot(100).unwrap();
+        let _second = cache.reserve_slot(101).unwrap();
+        for i in [0, 1, 0, 2, 0, 1, 3, 4, 0, 5] {
+            if cache.get(&i).is_none() {
+                cache.insert(i, i);
             }
+            check_invariants(&cache);
+            assert!(cache.hot_target() >= 1);
         }
-        self.hand_cold = self.ring.next_for_token(self.hand_cold);
-        while self.count_hot > self.capacity - self.cold_capacity {
-            self.run_hand_hot();
+        assert_eq!(cache.get(&5), Some(&5));
+    }
+
+    #[test]
+    fn test_iter_state() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
+        }
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        cache.remove(&5);
+        let count = |state| cache.iter_state(state).count();
+        assert_eq!(count(EntryState::Hot), cache.frequent_len());
+        assert_eq!(count(EntryState::Cold), cache.recent_len());
+        assert_eq!(count(EntryState::Test), cache.test_len());
+        assert!(cache.frequent_len() > 0 && cache.test_len() > 0);
+        assert!(cache
+            .iter_state(EntryState::Test)
+            .all(|(_, value)| value.is_none()));
+        assert!(cache
+            .iter_state(EntryState::Hot)
+            .all(|(key, value)| value == Some(key)));
+    }
+
+    #[test]
+    fn test_ghost_hit_promotes_to() {
+        for state in [EntryState::Hot, EntryState::Cold] {
+            let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(5)
+                .ghost_hit_promotes_to(state)
+                .build()
+                .unwrap();
+            for i in 0..8 {
+                cache.insert(i, i);
+            }
+            let ghost = (0..8)
+                .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Test))
+                .unwrap();
+            let occupancy = cache.occupancy();
+            cache.insert(ghost, ghost);
+            assert_eq!(cache.inspect(&ghost).unwrap().state, state);
+            assert_eq!(cache.len(), 5);
+            assert_eq!(cache.frequent_len() + cache.recent_len(), 5);
+            if state == EntryState::Hot {
+                assert_eq!(cache.frequent_len(), occupancy.hot + 1);
+            } else {
+                assert_eq!(cache.frequent_len(), occupancy.hot);
+            }
+        }
+        assert!(ClockProCache::<u64, u64>::builder(5)
+            .ghost_hit_promotes_to(EntryState::Test)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_evictable_len() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.evictable_len(), 10);
+        for i in 0..6 {
+            assert!(cache.pin(&i));
+        }
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.evictable_len(), 4);
+        cache.remove(&8);
+        assert_eq!(cache.evictable_len(), 3);
+        for i in 10..30 {
+            cache.insert(i, i);
+            assert_eq!(cache.evictable_len(), 4);
+        }
+        assert!(cache.unpin(&0));
+        assert_eq!(cache.evictable_len(), 5);
+    }
+
+    #[test]
+    fn test_into_hashmap() {
+        let mut cache: ClockProCache<u64, String> = ClockProCache::new(5).unwrap();
+        for i in 0..8 {
+            cache.insert(i, i.to_string());
+        }
+        cache.remove(&7);
+        let resident: Vec<_> = (0..8).filter(|key| cache.contains_key(key)).collect();
+        let map = cache.into_hashmap();
+        assert_eq!(map.len(), resident.len());
+        assert!(resident.iter().all(|key| map[key] == key.to_string()));
+    }
+
+    #[test]
+    fn test_contains_all() {
+        let mut cache: ClockProCache<String, u64> = ClockProCache::new(5).unwrap();
+        for i in 0..8 {
+            cache.insert(i.to_string(), i);
         }
+        let keys: Vec<String> = (0..10).rev().map(|i| i.to_string()).collect();
+        let refs: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let found = cache.contains_all(&refs);
+        let expected: Vec<bool> = refs.iter().map(|key| cache.contains_key(*key)).collect();
+        assert_eq!(found, expected);
+        assert_eq!(found.iter().filter(|&&found| found).count(), 5);
+        assert!(cache.contains_all::<str>(&[]).is_empty());
     }
 
-    fn run_hand_hot(&mut self) {
-        if self.hand_hot == self.hand_test {
-            self.run_hand_test();
-        }
-        {
-            let mentry = unsafe { self.slab[self.hand_hot].as_mut().unsafe_unwrap() };
-            if mentry.node_type.intersects(NodeType::HOT) {
-                if mentry.node_type.intersects(NodeType::REFERENCE) {
-                    mentry.node_type.remove(NodeType::REFERENCE);
-                } else {
-                    mentry.node_type.remove(NodeType::MASK);
-                    mentry.node_type.insert(NodeType::COLD);
-                    self.count_hot -= 1;
-                    self.count_cold += 1;
-                }
-            }
+    #[test]
+    fn test_reserve_exact() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        for i in 0..3 {
+            cache.insert(i, i);
         }
-        self.hand_hot = self.ring.next_for_token(self.hand_hot);
+        cache.reserve_exact(100);
+        assert_eq!(cache.slab.capacity(), cache.slab.len() + 100);
+        assert_eq!(cache.ring_capacity(), cache.ring_len() + 100);
+        assert!(cache.map.capacity() >= 103);
+
+        cache.reserve(200);
+        assert!(cache.slab.capacity() >= cache.slab.len() + 200);
+        assert!(cache.map.capacity() >= 203);
     }
 
-    fn run_hand_test(&mut self) {
-        if self.hand_test == self.hand_cold {
-            self.run_hand_cold();
+    #[test]
+    fn test_ring_len_and_capacity() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        assert_eq!(cache.ring_len(), 0);
+        assert!(cache.ring_capacity() >= 10);
+        for i in 0..20 {
+            cache.insert(i, i);
+            assert_eq!(cache.ring_len(), cache.total_tracked());
+            assert!(cache.ring_capacity() >= cache.ring_len());
         }
-        if unsafe {
-            self.slab[self.hand_test]
-                .as_ref()
-                .unsafe_unwrap()
-                .node_type
-                .intersects(NodeType::TEST)
-        } {
-            let prev = self.ring.prev_for_token(self.hand_test);
-            let hand_test = self.hand_test;
-            self.meta_del(hand_test);
-            self.hand_test = prev;
-            self.count_test -= 1;
-            if self.cold_capacity > 1 {
-                self.cold_capacity -= 1;
-            }
+        assert!(cache.set_capacity(50).is_ok());
+        for i in 20..200 {
+            cache.insert(i, i);
         }
-        self.hand_test = self.ring.next_for_token(self.hand_test);
+        assert_eq!(cache.ring_len(), cache.total_tracked());
+        assert!(cache.ring_capacity() >= 55);
     }
 
-    fn meta_del(&mut self, token: Token) {
-        {
-            let mentry = unsafe { self.slab[token].as_mut().unsafe_unwrap() };
-            mentry.node_type.remove(NodeType::MASK);
-            mentry.node_type.insert(NodeType::EMPTY);
-            mentry.value = None;
-            self.map.remove(&mentry.key);
-        }
-        if token == self.hand_hot {
-            self.hand_hot = self.ring.prev_for_token(self.hand_hot);
+    #[test]
+    fn test_peek_hot_sample() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
         }
-        if token == self.hand_cold {
-            self.hand_cold = self.ring.prev_for_token(self.hand_cold);
+        for i in 0..3 {
+            cache.insert(i, i);
         }
-        if token == self.hand_test {
-            self.hand_test = self.ring.prev_for_token(self.hand_test);
+        assert!(cache.frequent_len() > 0);
+        assert_eq!(cache.peek_hot_sample(), None);
+        let hot = (0..13)
+            .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Hot))
+            .unwrap();
+        cache.get(&hot);
+        assert_eq!(cache.peek_hot_sample(), Some((&hot, &hot)));
+    }
+
+    #[test]
+    fn test_strict_rejects_over_capacity() {
+        let mut cache: StrictClockProCache<u64, u64> = ClockProCache::new(3).unwrap().into();
+        for i in 0..3 {
+            assert_eq!(cache.insert(i, i), Ok(true));
         }
-        self.ring.remove(token);
-        self.evicted += 1;
+        assert!(cache.is_full());
+        assert_eq!(cache.insert(3, 3), Err((3, 3)));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.evicted(), 0);
+        assert_eq!(cache.insert(1, 10), Ok(false));
+        assert_eq!(cache.get(&1), Some(&10));
+        assert_eq!(cache.remove(&0), Some(0));
+        assert!(!cache.is_full());
+        assert_eq!(cache.insert(3, 3), Ok(true));
+        assert_eq!(cache.insert(4, 4), Err((4, 4)));
     }
-}
 
-unsafe impl<K, V> Send for ClockProCache<K, V>
-where
-    K: Send,
-    V: Send,
-{
-}
+    #[test]
+    fn test_panicking_predicates() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
 
-unsafe impl<K, V> Sync for ClockProCache<K, V>
-where
-    K: Sync,
-    V: Sync,
-{
-}
+        let fill = || {
+            let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+            for i in 0..13 {
+                cache.insert(i, i);
+            }
+            for i in 0..3 {
+                cache.insert(i, i);
+            }
+            cache
+        };
 
-mod token_ring {
-    use slab::Slab;
+        let mut cache = fill();
+        let mut calls = 0;
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            cache.retain(|_, _| {
+                calls += 1;
+                assert!(calls < 5, "predicate failure");
+                calls % 2 == 0
+            })
+        }));
+        assert!(result.is_err());
+        check_invariants(&cache);
+        assert_eq!(cache.len(), 8);
 
-    pub type Token = usize;
-    const TOKEN_THUMBSTONE: Token = !0;
+        let mut cache = fill();
+        let mut calls = 0;
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            cache.extract_if(|_, _| {
+                calls += 1;
+                assert!(calls < 5, "predicate failure");
+                true
+            })
+        }));
+        assert!(result.is_err());
+        check_invariants(&cache);
+        assert_eq!(cache.len(), 6);
 
-    pub struct Node {
-        next: Token,
-        prev: Token,
+        let mut cache = fill();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            cache.clear_with_callback(|_, _| panic!("callback failure"))
+        }));
+        assert!(result.is_err());
+        check_invariants(&cache);
+        assert!(cache.is_empty());
+        cache.insert(1, 1);
+        check_invariants(&cache);
+        assert_eq!(cache.get(&1), Some(&1));
     }
 
-    pub struct TokenRing {
-        head: Token,
-        tail: Token,
-        slab: Slab<Node>,
+    #[test]
+    fn test_normalized_stats() {
+        assert_eq!(CacheStats::default().miss_rate(), 0.0);
+        assert_eq!(CacheStats::default().evictions_per_1000_inserts(), 0.0);
+        let stats = CacheStats {
+            hits: 75,
+            misses: 25,
+            inserts: 400,
+            evictions: 30,
+            ..CacheStats::default()
+        };
+        assert!((stats.miss_rate() - 0.25).abs() < 1e-9);
+        assert!((stats.evictions_per_1000_inserts() - 75.0).abs() < 1e-9);
     }
 
-    impl TokenRing {
-        pub fn with_capacity(capacity: usize) -> Self {
-            if capacity < 1 {
-                panic!("A ring cannot have a capacity smaller than 1");
-            }
-            let slab = Slab::with_capacity(capacity);
-            TokenRing {
-                head: TOKEN_THUMBSTONE,
-                tail: TOKEN_THUMBSTONE,
-                slab,
-            }
+    #[test]
+    fn test_reserve_slot() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
         }
+        let ghost = (0..13)
+            .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Test))
+            .unwrap();
+        let reservation = cache.reserve_slot(ghost).unwrap();
+        assert_eq!(*reservation.key(), ghost);
+        assert_eq!(cache.get(&ghost), None);
+        assert!(cache.inspect(&ghost).is_none());
+        assert_eq!(cache.len(), 9);
+        assert_eq!(cache.reserve_slot(ghost).unwrap_err(), ghost);
+        let resident = (0..13)
+            .find(|key| cache.inspect(key).is_some_and(|info| info.value.is_some()))
+            .unwrap();
+        assert_eq!(cache.reserve_slot(resident).unwrap_err(), resident);
 
-        #[allow(dead_code)]
-        #[inline]
-        pub fn len(&self) -> usize {
-            self.slab.len()
+        // The reserved slot is not given away to new keys
+        for i in 100..120 {
+            cache.insert(i, i);
+            assert!(cache.len() <= 9);
         }
+        assert!(cache.fulfill(reservation, 42));
+        assert_eq!(cache.get(&ghost), Some(&42));
+        assert_eq!(cache.inspect(&ghost).unwrap().state, EntryState::Hot);
+        assert_eq!(cache.len(), 10);
 
-        #[inline]
-        pub fn next_for_token(&self, token: Token) -> Token {
-            let next = self.slab[token].next;
-            if next == TOKEN_THUMBSTONE {
-                assert!(self.head != TOKEN_THUMBSTONE);
-                self.head
-            } else {
-                next
-            }
+        let reservation: SlotReservation<u64> = cache.reserve_slot(200).unwrap();
+        assert_eq!(cache.len(), 9);
+        assert_eq!(cache.get(&200), None);
+        cache.cancel_reservation(reservation);
+        cache.insert(300, 300);
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.get(&200), None);
+
+        // Reservations leave two slots to the hands
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(4).unwrap();
+        let _first = cache.reserve_slot(100).unwrap();
+        let _second = cache.reserve_slot(101).unwrap();
+        assert_eq!(cache.reserve_slot(102).unwrap_err(), 102);
+        assert!(cache.set_capacity(3).is_err());
+        assert!(cache.rebuild_with_capacity(3).is_err());
+        for i in [0, 1, 0, 1, 2, 0, 3, 1, 2] {
+            cache.insert(i, i);
+            check_invariants(&cache);
         }
+        assert_eq!(cache.len(), 2);
 
-        #[inline]
-        pub fn prev_for_token(&self, token: Token) -> Token {
-            let prev = self.slab[token].prev;
-            if prev == TOKEN_THUMBSTONE {
-                assert!(self.tail != TOKEN_THUMBSTONE);
-                self.tail
-            } else {
-                prev
-            }
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        let _reservation = cache.reserve_slot(100).unwrap();
+        assert_eq!(cache.reserve_slot(101).unwrap_err(), 101);
+        for i in [0, 1, 0, 1] {
+            cache.insert(i, i);
+            check_invariants(&cache);
         }
 
-        pub fn remove(&mut self, token: Token) {
-            let (prev, next) = (self.slab[token].prev, self.slab[token].next);
-            if prev != TOKEN_THUMBSTONE {
-                self.slab[prev].next = next;
-            } else {
-                self.head = next;
-            }
-            if next != TOKEN_THUMBSTONE {
-                self.slab[next].prev = prev;
-            } else {
-                self.tail = prev;
-            }
-            self.slab[token].prev = TOKEN_THUMBSTONE;
-            self.slab[token].next = TOKEN_THUMBSTONE;
-            self.slab.remove(token);
+        // Without ghost history, the test hand can forget every entry while
+        // the hands make room next to a reservation
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(16)
+            .test_capacity(2)
+            .shrink_ghosts_with_capacity(true)
+            .build()
+            .unwrap();
+        for i in 0..4 {
+            cache.insert(i, i);
+        }
+        cache.import_keys(100..102);
+        let _reservation = cache.reserve_slot(200).unwrap();
+        cache.rebuild_with_capacity(3).unwrap();
+        check_invariants(&cache);
+        for i in 10..20 {
+            cache.insert(i, i);
+            check_invariants(&cache);
         }
+        assert_eq!(cache.len(), 2);
+    }
 
-        pub fn insert_after(&mut self, to: Token) -> Token {
-            if self.slab.is_empty() {
-                let node = Node {
-                    prev: TOKEN_THUMBSTONE,
-                    next: TOKEN_THUMBSTONE,
-                };
-                let token = self.slab.insert(node);
-                self.head = token;
-                self.tail = token;
-                return token;
-            }
-            let to_prev = self.slab[to].prev;
-            let old_second = to_prev;
-            if old_second == TOKEN_THUMBSTONE {
-                let old_second = self.tail;
-                let node = Node {
-                    prev: old_second,
-                    next: TOKEN_THUMBSTONE,
-                };
-                let token = self.slab.insert(node);
-                self.slab[old_second].next = token;
-                self.tail = token;
-                token
-            } else {
-                let node = Node {
-                    prev: old_second,
-                    next: to,
-                };
-                let token = self.slab.insert(node);
-                self.slab[old_second].next = token;
-                self.slab[to].prev = token;
-                token
-            }
+    #[test]
+    fn test_fold_values() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        assert_eq!(cache.fold_values(0, |sum, value| sum + value), 0);
+        for i in 0..13 {
+            cache.insert(i, i);
         }
+        cache.remove(&12);
+        let expected: u64 = (0..13)
+            .filter_map(|key| cache.inspect(&key).and_then(|info| info.value.copied()))
+            .sum();
+        assert_eq!(cache.len(), 9);
+        assert_eq!(cache.fold_values(0, |sum, value| sum + value), expected);
+        assert_eq!(cache.fold_values(0, |count, _| count + 1), 9);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::ClockProCache;
+    #[test]
+    fn test_get_by_handle() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        assert_eq!(cache.handle(&0), None);
+        cache.insert(0, 0);
+        let handle: Handle = cache.handle(&0).unwrap();
+        for _ in 0..3 {
+            *cache.get_by_handle(handle).unwrap() += 1;
+        }
+        assert_eq!(cache.get(&0), Some(&3));
+        assert_eq!(cache.stats().hits, 4);
+        assert_eq!(cache.handle(&0), Some(handle));
+
+        // The slot freed by the removal is reused by the next insertion
+        cache.remove(&0);
+        assert_eq!(cache.get_by_handle(handle), None);
+        cache.insert(1, 1);
+        assert_eq!(cache.map[&1], handle.token);
+        assert_eq!(cache.get_by_handle(handle), None);
+        cache.insert(0, 0);
+        assert_eq!(cache.get_by_handle(handle), None);
+        let handle = cache.handle(&0).unwrap();
+
+        // Eviction invalidates handles as well
+        for i in 10..20 {
+            cache.insert(i, i);
+        }
+        assert_eq!(cache.get_by_handle(handle), None);
+    }
 
     #[test]
-    fn test_cache() {
-        let mut cache = ClockProCache::new(3).unwrap();
-        cache.insert("testkey", "testvalue");
-        assert!(cache.contains_key("testkey"));
-        cache.insert("testkey2", "testvalue2");
-        assert!(cache.contains_key("testkey2"));
-        cache.insert("testkey3", "testvalue3");
-        assert!(cache.contains_key("testkey3"));
-        cache.insert("testkey4", "testvalue4");
-        assert!(cache.contains_key("testkey4"));
-        assert!(cache.contains_key("testkey3"));
-        assert!(!cache.contains_key("testkey2"));
-        cache.insert("testkey", "testvalue");
-        assert!(cache.get_mut("testkey").is_some());
-        assert!(cache.get_mut("testkey-nx").is_none());
+    fn test_clear_resident() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        for i in 0..13 {
+            cache.insert(i, i);
+        }
+        cache.pin(&12);
+        let tracked = cache.total_tracked();
+        cache.clear_resident();
+        check_invariants(&cache);
+        assert!(cache.is_empty());
+        assert_eq!(cache.pinned_len(), 0);
+        assert_eq!(cache.get(&12), None);
+        assert_eq!(cache.test_len(), tracked.min(cache.test_capacity()));
+
+        let ghost = (0..13)
+            .find(|key| cache.inspect(key).map(|info| info.state) == Some(EntryState::Test))
+            .unwrap();
+        cache.insert(ghost, 0);
+        assert_eq!(cache.inspect(&ghost).unwrap().state, EntryState::Hot);
+        check_invariants(&cache);
+
+        // Dropping every ghost empties the ring
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(5).unwrap();
+        for i in 0..5 {
+            cache.insert(i, i);
+        }
+        cache.clear_resident();
+        assert!(cache.set_test_capacity(0).is_ok());
+        check_invariants(&cache);
+        assert_eq!(cache.total_tracked(), 0);
+        let mut cache: ClockProCache<u64, u64> =
+            ClockProCache::builder(5).test_capacity(0).build().unwrap();
+        for i in 0..5 {
+            cache.insert(i, i);
+        }
+        cache.clear_resident();
+        check_invariants(&cache);
+        assert_eq!(cache.total_tracked(), 0);
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+        check_invariants(&cache);
+        assert_eq!(cache.len(), 5);
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(9).unwrap();
+        for i in 0..20 {
+            cache.insert(i, i);
+        }
+        cache.clear_resident();
+        cache.insert(28, 28);
+        assert!(cache.set_test_capacity(0).is_ok());
+        check_invariants(&cache);
+        assert_eq!(cache.get(&28), Some(&28));
+        assert_eq!(cache.total_tracked(), 1);
+
+        // Plain CLOCK forgets the entries instead
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(5)
+            .eviction_policy(EvictionPolicy::Clock)
+            .build()
+            .unwrap();
+        for i in 0..5 {
+            cache.insert(i, i);
+        }
+        cache.clear_resident();
+        check_invariants(&cache);
+        assert_eq!(cache.total_tracked(), 0);
+        for i in (0..5).chain(10..20) {
+            cache.insert(i, i);
+        }
+        check_invariants(&cache);
+        assert_eq!(cache.len(), 5);
     }
 
     #[test]
-    fn test_recycle() {
-        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
-        for i in 0..7 {
-            assert!(cache.insert(i, i));
+    fn test_get_or_insert_with_under_eviction() {
+        // Shrinking the test capacity used to move the cold hand right after
+        // a new entry was linked, which could evict that very entry
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::builder(3)
+            .adaptive_test_capacity(6)
+            .build()
+            .unwrap();
+        let mut state = 9u64;
+        for _ in 0..5000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let key = state % 15;
+            assert_eq!(*cache.get_or_insert_with(key, || key * 10), key * 10);
+            assert_eq!(
+                cache.peek_mut_guard(&key).map(|value| *value),
+                Some(key * 10)
+            );
         }
-        for i in 0..2 {
-            match cache.get(&i) {
-                None => {}
-                Some(x) => assert_eq!(*x, i),
+        check_invariants(&cache);
+        assert!(cache.evicted() > 0);
+    }
+
+    #[test]
+    fn test_aging_interval() {
+        let run = |aging: Option<usize>| {
+            let mut builder = ClockProCache::builder(5).frequency_counter_limit(200);
+            if let Some(n) = aging {
+                builder = builder.aging_interval(n);
+            }
+            let mut cache: ClockProCache<u64, u64> = builder.build().unwrap();
+            cache.insert(0, 0);
+            for _ in 0..200 {
+                cache.get(&0);
             }
+            // Key 1 is the popular one now, among a stream of one-off keys
+            for i in 0..100 {
+                if cache.get(&1).is_none() {
+                    cache.insert(1, 1);
+                }
+                cache.insert(100 + i, i);
+                cache.get(&(100 + i));
+            }
+            (cache.contains_key(&0), cache.contains_key(&1))
+        };
+        assert_eq!(run(None), (true, true));
+        assert_eq!(run(Some(20)), (false, true));
+
+        assert!(ClockProCache::<u64, u64>::builder(5)
+            .aging_interval(20)
+            .build()
+            .is_err());
+        assert!(ClockProCache::<u64, u64>::builder(5)
+            .frequency_counter_limit(3)
+            .aging_interval(0)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_insert_and_get_mut() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(3).unwrap();
+        for i in 0..3 {
+            cache.insert(i, i);
         }
+        *cache.insert_and_get_mut(3, 30) += 1;
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.peek_mut_guard(&3).map(|value| *value), Some(31));
+        assert!(referenced(&cache, &3));
+
+        *cache.insert_and_get_mut(3, 40) += 1;
+        assert_eq!(cache.get(&3), Some(&41));
     }
 
     #[test]
-    fn test_composite() {
-        let mut cache: ClockProCache<u64, (Vec<u8>, u64)> = ClockProCache::new(3).unwrap();
-        for i in 0..7 {
-            assert!(cache.insert(i, (vec![0u8; 12], i)));
+    fn test_iter_limited() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        assert_eq!(cache.iter_limited(5).count(), 0);
+        for i in 0..13 {
+            cache.insert(i, i);
         }
-        for i in 0..2 {
-            match cache.get(&i) {
-                None => {}
-                Some(x) => assert_eq!(x.1, i),
-            }
+        assert_eq!(cache.iter_limited(0).count(), 0);
+        assert_eq!(cache.iter_limited(4).count(), 4);
+        assert_eq!(cache.iter_limited(100).count(), cache.len());
+        for (key, value) in cache.iter_limited(100) {
+            assert_eq!(key, value);
+            assert_eq!(cache.inspect(key).unwrap().value, Some(value));
         }
     }
 
     #[test]
-    fn test_remove() {
-        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(4).unwrap();
-        for i in 0..4 {
-            assert!(cache.insert(i, i));
+    fn test_slab_growth() {
+        let build = |growth| -> ClockProCache<u64, u64> {
+            ClockProCache::builder(100)
+                .test_capacity(100)
+                .slab_growth(growth)
+                .build()
+                .unwrap()
+        };
+
+        let mut cache = build(SlabGrowth::Increment(16));
+        assert_eq!(cache.slab.capacity(), 16);
+        for i in 0..40 {
+            cache.insert(i, i);
+            check_invariants(&cache);
         }
+        assert_eq!(cache.slab.capacity(), 48);
+        assert_eq!(cache.ring_capacity(), 48);
+        cache.clear_with_callback(|_, _| {});
+        assert_eq!(cache.slab.capacity(), 16);
+        assert_eq!(cache.ring_capacity(), 16);
 
-        assert_eq!(cache.remove(&2), Some(2));
-        assert_eq!(cache.remove(&3), Some(3));
-        assert_eq!(cache.remove(&3), None);
+        let mut cache = build(SlabGrowth::Preallocate);
+        cache.set_capacity(1000).unwrap();
+        let (slab, ring) = (cache.slab.capacity(), cache.ring_capacity());
+        assert!(slab >= 1100 && ring >= 1100);
+        for i in 0..2000 {
+            cache.insert(i, i);
+        }
+        assert_eq!((cache.slab.capacity(), cache.ring_capacity()), (slab, ring));
+        cache.clear_with_callback(|_, _| {});
+        assert!(cache.slab.capacity() >= 1100 && cache.ring_capacity() >= 1100);
 
-        for i in 0..4 {
-            match i {
-                2 | 3 => assert_eq!(cache.get(&i), None),
-                _ => assert_eq!(*cache.get(&i).unwrap(), i),
-            };
+        let mut cache = build(SlabGrowth::Doubling);
+        assert!(cache.slab.capacity() >= 200);
+        cache.set_capacity(1000).unwrap();
+        assert!(cache.slab.capacity() < 1100);
+        for i in 0..2000 {
+            cache.insert(i, i);
         }
+        assert!(cache.slab.capacity() >= 1100);
 
-        // Reinsert removed entries
-        for i in 2..4 {
-            assert!(cache.insert(i, i));
+        assert!(ClockProCache::<u64, u64>::builder(100)
+            .slab_growth(SlabGrowth::Increment(0))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_cold_capacity_bounds() {
+        let mut cache: ClockProCache<u64, u64> = ClockProCache::new(10).unwrap();
+        let in_bounds = |cache: &ClockProCache<u64, u64>| {
+            (1..cache.capacity).contains(&cache.cold_capacity)
+                && cache.hot_target() <= cache.clock_capacity()
+        };
+        // Looping over slightly more keys than the cache holds keeps hitting
+        // ghosts, which grows the cold capacity up to its bound
+        let mut at_bound = false;
+        for i in 0..600 {
+            cache.insert(i % 12, i);
+            assert!(in_bounds(&cache));
+            if cache.cold_capacity == 9 {
+                at_bound = true;
+                break;
+            }
         }
+        assert!(at_bound);
+        assert!(cache.set_capacity(4).is_ok());
+        assert_eq!(cache.cold_capacity, 3);
+        assert!(in_bounds(&cache));
 
-        // Check that all entries still exist
-        for i in 0..4 {
-            assert_eq!(*cache.get(&i).unwrap(), i);
+        // A scan only forgets ghosts, which shrinks it down to its bound
+        assert!(cache.set_capacity(10).is_ok());
+        for i in 100..1000 {
+            cache.insert(i, i);
+            assert!(in_bounds(&cache));
         }
+        assert_eq!(cache.cold_capacity, 1);
     }
 }